@@ -0,0 +1,422 @@
+//! Aggregation modules.
+//!
+//! BMP aggregates the messages of an Aggregation module in tumbling windows of `window` and per
+//! `key`: one message per key and window comes out, the first message of the bucket with its
+//! `sums` written at their paths. Windows follow the calendar of the `time_zone` of the config,
+//! like the cycles of the Reporting modules: windows of whole days start at local midnight and
+//! are counted from 1970-01-01, shorter ones are counted from the start of their day, and the
+//! last one of a day ends at midnight.
+//!
+//! A window closes when the latest event time seen, the watermark, passes its end plus the
+//! `allowed_lateness` of the module in the config. Closed windows come out in order, and the
+//! messages that arrive after their window closed go to its `late_stream`. The windows still open
+//! at the end of the input are closed then.
+
+use std::collections::{BTreeMap, HashMap};
+
+use jiff::{civil::Date, ToSpan};
+use serde_json::{json, Number, Value};
+
+use crate::{
+    datapath::{self, DataPath},
+    datetime::{parse_period, DateTime, TimeZone},
+    ingestion::EventTime,
+    opts::Message,
+    playbook::{EventTimeField, Module, SumSource},
+};
+
+const DAY: i64 = 86400;
+
+#[derive(Debug, Clone, Copy)]
+enum Total {
+    Int(i64),
+    Float(f64),
+}
+
+impl Total {
+    fn add(self, other: Total) -> Total {
+        match (self, other) {
+            (Total::Int(a), Total::Int(b)) => match a.checked_add(b) {
+                Some(x) => Total::Int(x),
+                None => Total::Float(a as f64 + b as f64),
+            },
+            (a, b) => Total::Float(a.as_f64() + b.as_f64()),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Total::Int(x) => x as f64,
+            Total::Float(x) => x,
+        }
+    }
+
+    fn of(value: &Value) -> Option<Total> {
+        match value {
+            Value::Null => Some(Total::Int(0)),
+            Value::Number(x) => x.as_i64().map(Total::Int).or(x.as_f64().map(Total::Float)),
+            Value::String(x) => x.trim().parse().map(Total::Int).ok().or_else(|| {
+                x.trim()
+                    .parse()
+                    .ok()
+                    .filter(|x: &f64| x.is_finite())
+                    .map(Total::Float)
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<Total> for Value {
+    fn from(total: Total) -> Self {
+        match total {
+            Total::Int(x) => x.into(),
+            Total::Float(x) => Number::from_f64(x).map_or(Value::Null, Value::Number),
+        }
+    }
+}
+
+struct Bucket {
+    first: Message,
+    count: u64,
+    totals: Vec<Total>,
+}
+
+struct Window {
+    end: DateTime,
+    index: HashMap<String, usize>,
+    buckets: Vec<Bucket>,
+}
+
+pub struct Aggregator {
+    key: Vec<DataPath>,
+    sums: Vec<(DataPath, Option<DataPath>)>,
+    window: i64,
+    lateness: i64,
+    event_time: EventTime,
+    zone: TimeZone,
+    late_stream: String,
+    watermark: Option<DateTime>,
+    windows: BTreeMap<DateTime, Window>,
+}
+
+impl Aggregator {
+    pub fn new(module: &Module, zone: TimeZone) -> Result<Self, String> {
+        let Module::Aggregation {
+            key,
+            sums,
+            window,
+            event_time,
+            allowed_lateness,
+            late_stream,
+            ..
+        } = module
+        else {
+            return Err(format!("{} isn't an Aggregation module", module.name()));
+        };
+
+        let key = datapath::parse_all(key).map_err(|e| format!("{e} in key"))?;
+        if let Some(path) = key.iter().find(|x| {
+            let x = x.to_string();
+            x == "billingmediation" || x.starts_with("billingmediation.aggregation")
+        }) {
+            return Err(format!("{path} can't be part of the key"));
+        }
+        let sums = sums
+            .iter()
+            .map(|(target, source)| {
+                let target = target
+                    .parse()
+                    .map_err(|e| format!("invalid sum path {target}: {e}"))?;
+                let source = match source {
+                    SumSource::Count(1) => None,
+                    SumSource::Count(n) => {
+                        return Err(format!("sum {target} must be a path or 1, not {n}"))
+                    }
+                    SumSource::Path(path) => Some(
+                        path.parse()
+                            .map_err(|e| format!("invalid sum path {path}: {e}"))?,
+                    ),
+                };
+                Ok((target, source))
+            })
+            .collect::<Result<_, String>>()?;
+        let window = parse_period(window)?;
+        if window <= 0 {
+            return Err(format!("the window must be positive, got {window}s"));
+        }
+        if window > DAY && window % DAY != 0 {
+            return Err("windows longer than a day must be whole days".to_owned());
+        }
+        let lateness = allowed_lateness.as_deref().map(parse_period).transpose()?;
+        if lateness.is_some_and(|x| x < 0) {
+            return Err("allowed_lateness can't be negative".to_owned());
+        }
+        let default = EventTimeField {
+            path: String::new(),
+            format: None,
+        };
+        let event_time = EventTime::field(event_time.as_ref().unwrap_or(&default), zone.clone())
+            .map_err(|e| format!("invalid event_time: {e}"))?;
+
+        Ok(Self {
+            key,
+            sums,
+            window,
+            lateness: lateness.unwrap_or(0),
+            event_time,
+            zone,
+            late_stream: late_stream.clone(),
+            watermark: None,
+            windows: BTreeMap::new(),
+        })
+    }
+
+    pub fn push(&mut self, mut msg: Message, emit: &mut impl FnMut(&str, Message)) {
+        let time = match self.event_time.time(&msg) {
+            Ok(time) => time,
+            Err(reason) => return error(msg, reason, emit),
+        };
+        let (start, end) = self.window_of(&time);
+        if self
+            .watermark
+            .is_some_and(|x| end.add_seconds(self.lateness) <= x)
+        {
+            return emit(&self.late_stream, msg);
+        }
+        let values = self
+            .sums
+            .iter()
+            .map(|(_, source)| {
+                let Some(path) = source else {
+                    return Ok(Total::Int(1));
+                };
+                let value = path
+                    .get(&msg)
+                    .map_err(|e| format!("can't sum {path}: {e}"))?;
+                Total::of(&value).ok_or_else(|| {
+                    format!(
+                        "can't sum {path}, it's {}",
+                        datapath::a(datapath::kind(&value))
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>();
+        let values = match values {
+            Ok(values) => values,
+            Err(reason) => return error(msg, reason, emit),
+        };
+
+        // Values aren't Hash, their json is
+        let key = match datapath::key_of(&msg, &self.key) {
+            Ok(key) => Value::from(key).to_string(),
+            Err(e) => return error(msg, format!("no key: {e}"), emit),
+        };
+        let window = self.windows.entry(start).or_insert_with(|| Window {
+            end,
+            index: HashMap::new(),
+            buckets: vec![],
+        });
+        let bucket = match window.index.get(&key) {
+            Some(&i) => &mut window.buckets[i],
+            None => {
+                window.index.insert(key, window.buckets.len());
+                msg.inner.billingmediation.remove("aggregation");
+                window.buckets.push(Bucket {
+                    first: msg,
+                    count: 0,
+                    totals: vec![Total::Int(0); values.len()],
+                });
+                window.buckets.last_mut().unwrap()
+            }
+        };
+        bucket.count += 1;
+        for (total, value) in bucket.totals.iter_mut().zip(values) {
+            *total = total.add(value);
+        }
+
+        let watermark = self.watermark.map_or(time, |x| x.max(time));
+        self.watermark = Some(watermark);
+        let lateness = self.lateness;
+        self.close(|end| end.add_seconds(lateness) <= watermark, emit);
+    }
+
+    fn window_of(&self, time: &DateTime) -> (DateTime, DateTime) {
+        let day = time.in_zone(&self.zone).local().date();
+        // Every civil time has an instant, when clocks go forward at midnight it's the end of
+        // the gap
+        let midnight = |x: Date| DateTime::from_local(x.into(), &self.zone).unwrap();
+        if self.window % DAY == 0 {
+            let days = self.window / DAY;
+            let epoch = jiff::civil::date(1970, 1, 1);
+            let since = day.since(epoch).map_or(0, |x| x.get_days() as i64);
+            let start = epoch.saturating_add((since.div_euclid(days) * days).days());
+            return (midnight(start), midnight(start.saturating_add(days.days())));
+        }
+        let (day_start, day_end) = (midnight(day), midnight(day.saturating_add(1.day())));
+        let offset = (time.timestamp() - day_start.timestamp()).div_euclid(self.window);
+        let start = day_start.add_seconds(offset * self.window);
+        let end = start.add_seconds(self.window).min(day_end);
+        (start.in_zone(&self.zone), end.in_zone(&self.zone))
+    }
+
+    pub fn finish(mut self, emit: &mut impl FnMut(&str, Message)) {
+        self.close(|_| true, emit);
+    }
+
+    fn close(&mut self, done: impl Fn(DateTime) -> bool, emit: &mut impl FnMut(&str, Message)) {
+        while let Some(entry) = self.windows.first_entry() {
+            if !done(entry.get().end) {
+                break;
+            }
+            let (start, window) = entry.remove_entry();
+            for bucket in window.buckets {
+                match self.aggregate(start, window.end, bucket) {
+                    Ok(msg) => emit("output", msg),
+                    Err((msg, reason)) => error(msg, reason, emit),
+                }
+            }
+        }
+    }
+
+    fn aggregate(
+        &self,
+        start: DateTime,
+        end: DateTime,
+        bucket: Bucket,
+    ) -> Result<Message, (Message, String)> {
+        let mut msg = bucket.first;
+        for ((target, _), total) in self.sums.iter().zip(bucket.totals) {
+            if let Err(e) = target.set(&mut msg, total.into()) {
+                return Err((msg, format!("can't write sum {target}: {e}")));
+            }
+        }
+        msg.inner.billingmediation.insert(
+            "aggregation".to_owned(),
+            json!({
+                "windowStart": start.to_string(),
+                "windowEnd": end.to_string(),
+                "count": bucket.count,
+            }),
+        );
+        Ok(msg)
+    }
+}
+
+fn error(mut msg: Message, reason: String, emit: &mut impl FnMut(&str, Message)) {
+    tracing::debug!("{reason}");
+    msg.inner
+        .billingmediation
+        .insert("error".to_owned(), reason.into());
+    emit("error", msg);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opts::{MessageInner, ModuleOpts};
+
+    fn msg(date: &str, payload: &str) -> Message {
+        Message {
+            inner: MessageInner::from(payload.to_owned()),
+            date: date.into(),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn hourly_windows() {
+        let mut module: Module = serde_json::from_value(json!({
+            "type": "Aggregation",
+            "name": "usage",
+            "key": ["customer"],
+            "sums": {"total": "bytes", "calls": 1},
+            "window": "PT1H",
+        }))
+        .unwrap();
+        module
+            .set_opts(&ModuleOpts {
+                allowed_lateness: Some("PT10M".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut aggregator = Aggregator::new(&module, TimeZone::UTC).unwrap();
+        let mut out = vec![];
+        let mut emit = |route: &str, msg: Message| {
+            let agg = msg.inner.billingmediation.get("aggregation").cloned();
+            out.push((route.to_owned(), msg.inner.payload.to_string(), agg));
+        };
+        let messages = [
+            ("2024-04-01T10:05:00Z", r#"{"customer": "a", "bytes": 10}"#),
+            (
+                "2024-04-01T10:20:00Z",
+                r#"{"customer": "b", "bytes": "2.5"}"#,
+            ),
+            ("2024-04-01T10:40:00Z", r#"{"customer": "a", "bytes": 5}"#),
+            // Closes 10:00 once the lateness is over
+            ("2024-04-01T11:15:00Z", r#"{"customer": "a", "bytes": 1}"#),
+            ("2024-04-01T10:50:00Z", r#"{"customer": "a", "bytes": 100}"#),
+            ("2024-04-01T11:20:00Z", r#"{"customer": "a", "bytes": []}"#),
+            ("2024-04-01T11:25:00Z", r#"{"customer": "a"}"#),
+            ("2024-04-01T11:30:00Z", r#"{"client": "a", "bytes": 1}"#),
+        ];
+        for (date, payload) in messages {
+            aggregator.push(msg(date, payload), &mut emit);
+        }
+        aggregator.finish(&mut emit);
+
+        let routes = out.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            routes,
+            ["output", "output", "late", "error", "error", "error", "output"]
+        );
+        assert!(out[0].1.contains(r#""total":15"#) && out[0].1.contains(r#""calls":2"#));
+        assert!(out[1].1.contains(r#""total":2.5"#));
+        assert_eq!(
+            out[0].2,
+            Some(json!({
+                "windowStart": "2024-04-01T10:00:00Z",
+                "windowEnd": "2024-04-01T11:00:00Z",
+                "count": 2,
+            }))
+        );
+    }
+
+    #[test]
+    fn daily_windows_follow_the_zone() {
+        let module: Module = serde_json::from_value(json!({
+            "type": "Aggregation",
+            "name": "daily",
+            "key": ["customer"],
+            "sums": {"calls": 1},
+            "window": "P1D",
+        }))
+        .unwrap();
+        let paris: TimeZone = "Europe/Paris".parse().unwrap();
+        let mut aggregator = Aggregator::new(&module, paris).unwrap();
+        let mut out = vec![];
+        let mut emit = |_: &str, msg: Message| {
+            out.push(msg.inner.billingmediation["aggregation"].clone());
+        };
+        // 23:30 UTC is the next day in Paris, and the day clocks go back lasts 25 hours
+        for date in [
+            "2024-10-26T21:30:00Z",
+            "2024-10-26T22:30:00Z",
+            "2024-10-27T22:59:00Z",
+            "2024-10-27T23:00:00Z",
+        ] {
+            aggregator.push(msg(date, r#"{"customer": "a"}"#), &mut emit);
+        }
+        aggregator.finish(&mut emit);
+
+        let window = |start: &str, end: &str, count: u64| json!({"windowStart": start, "windowEnd": end, "count": count});
+        assert_eq!(
+            out,
+            [
+                window("2024-10-26T00:00:00+02:00", "2024-10-27T00:00:00+02:00", 1),
+                window("2024-10-27T00:00:00+02:00", "2024-10-28T00:00:00+01:00", 2),
+                window("2024-10-28T00:00:00+01:00", "2024-10-29T00:00:00+01:00", 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::VecDeque, path::Path, process::Stdio};
+
+use eyre::Context;
+use itertools::Itertools;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, Command},
+};
+use tracing::{debug, trace};
+
+use crate::opts::{AnonymizerOpts, Payload};
+
+pub type AnonLine = Result<Payload, String>;
+
+fn spawn(opts: &AnonymizerOpts, grammar: &Path) -> eyre::Result<Child> {
+    let nul = if cfg!(windows) {
+        "NUL"
+    } else if cfg!(unix) {
+        "/dev/null"
+    } else {
+        eyre::bail!("unsupported system")
+    };
+
+    let mut cmd = Command::new(&opts.java);
+    cmd.arg(format!("-DlogFile={nul}"))
+        .args(&opts.jvm_opts)
+        .arg("-jar")
+        .arg(&opts.jar)
+        .arg(&opts.command)
+        .args(["--input-file", "-", "--grammar-file"])
+        .arg(grammar);
+    debug!("{cmd:?}");
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .wrap_err_with(|| format!("couldn't start {:?}, is a JVM installed?", opts.java))
+}
+
+fn decode_line(line: &[u8]) -> AnonLine {
+    let line = line.trim_ascii();
+    if line.is_empty() {
+        return Err("the anonymization tool printed nothing for this line".to_owned());
+    }
+    std::str::from_utf8(line)
+        .map(|x| Payload::from(x.to_owned()))
+        .map_err(|e| format!("output is not utf8 at byte {}", e.valid_up_to()))
+}
+
+/// Runs the anonymization tool over `input`, which has `expected` lines.
+///
+/// The tool prints one line per input line and in the same order, which is what correlates them:
+/// output line n belongs to input line n, blank ones included. When a line makes the tool print
+/// more or less than one line, there's no telling which output goes with which line, so both
+/// halves of the input are run again on their own until the lines at fault are found alone. Only
+/// those are rejected. A dropped line and a doubled one in the same run cancel out and go
+/// unnoticed.
+pub async fn anonymize(
+    input: &str,
+    expected: usize,
+    grammar: &Path,
+    opts: &AnonymizerOpts,
+) -> eyre::Result<Vec<AnonLine>> {
+    let (ret, stderr) = run_tool(input, grammar, opts).await?;
+    trace!("Anonymization tool returned {} lines", ret.len());
+    if ret.len() == expected {
+        return Ok(ret);
+    }
+    if expected == 1 {
+        let reason = format!(
+            "the anonymization tool printed {} lines for this line: {stderr}",
+            ret.len()
+        );
+        return Ok(vec![Err(reason)]);
+    }
+
+    let lines = input.split('\n').collect_vec();
+    if lines.len() != expected {
+        eyre::bail!("the input has {} lines, not {expected}", lines.len());
+    }
+    debug!(
+        "the anonymization tool returned {} lines for {expected}, splitting the input",
+        ret.len()
+    );
+    let (a, b) = lines.split_at(expected / 2);
+    let mut ret = Box::pin(anonymize(&a.join("\n"), a.len(), grammar, opts)).await?;
+    ret.extend(Box::pin(anonymize(&b.join("\n"), b.len(), grammar, opts)).await?);
+    Ok(ret)
+}
+
+/// Runs the tool once, returning what it printed and the end of its stderr. Stdin is written
+/// while stdout is being read, otherwise the tool blocks on a full stdout pipe while we block on
+/// a full stdin one.
+async fn run_tool(
+    input: &str,
+    grammar: &Path,
+    opts: &AnonymizerOpts,
+) -> eyre::Result<(Vec<AnonLine>, String)> {
+    let mut child = spawn(opts, grammar)?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let write = async move {
+        stdin.write_all(input.as_bytes()).await?;
+        // Closing stdin is what tells the tool that the input is over
+        stdin.shutdown().await
+    };
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let read = async move {
+        let mut ret: Vec<AnonLine> = vec![];
+        let mut buf = vec![];
+        while stdout.read_until(b'\n', &mut buf).await? != 0 {
+            ret.push(decode_line(&buf));
+            buf.clear();
+        }
+        Ok::<_, std::io::Error>(ret)
+    };
+
+    // Only the end of stderr is kept, for the error message
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    let errors = async move {
+        let mut tail = VecDeque::new();
+        while let Ok(Some(line)) = stderr.next_line().await {
+            trace!("anonymization tool: {line}");
+            if tail.len() == 20 {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        tail
+    };
+
+    let (written, read, stderr) = tokio::join!(write, read, errors);
+    let status = child
+        .wait()
+        .await
+        .wrap_err("waiting for the anonymization tool")?;
+    let stderr = stderr.iter().join("\n");
+    if !status.success() {
+        eyre::bail!("the anonymization tool exited with {status}:\n{stderr}");
+    }
+    written.wrap_err("writing to the anonymization tool")?;
+    let ret = read.wrap_err("reading from the anonymization tool")?;
+    Ok((ret, stderr))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_the_lines_the_tool_skips_are_rejected() {
+        // Stands in for java, drops the lines with "bad"
+        let dir = std::env::temp_dir().join(format!("anon-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("java");
+        let script = r#"#!/bin/sh
+while IFS= read -r l || [ -n "$l" ]; do
+    case "$l" in
+        *bad*) ;;
+        *) echo "{\"l\":\"$l\"}" ;;
+    esac
+done"#;
+        std::fs::write(&tool, script).unwrap();
+        std::fs::set_permissions(&tool, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let opts = AnonymizerOpts {
+            java: tool,
+            ..Default::default()
+        };
+
+        let input = (0..9)
+            .map(|n| {
+                if n == 4 {
+                    "bad".to_owned()
+                } else {
+                    n.to_string()
+                }
+            })
+            .join("\n");
+        let res = anonymize(&input, 9, Path::new("grammar"), &opts)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let ok = |n: usize| format!(r#"{{"l":"{n}"}}"#);
+        for n in [0, 1, 2, 3, 5, 6, 7, 8] {
+            assert_eq!(res[n].as_deref(), Ok(ok(n).as_str()));
+        }
+        assert!(res[4].as_ref().unwrap_err().contains("printed 0 lines"));
+    }
+}
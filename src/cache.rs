@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+use crate::opts::{AnonymizerOpts, CacheOpts};
+
+/// Bump this when the format of the entries changes
+const VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub size: u64,
+    pub created: u64,
+    pub last_used: u64,
+    pub input: String,
+    pub grammar: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    entries: HashMap<String, Entry>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            entries: Default::default(),
+        }
+    }
+}
+
+pub struct Cache {
+    opts: CacheOpts,
+    manifest: Mutex<Manifest>,
+    dirty: AtomicBool,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Cache {
+    pub fn open(opts: &CacheOpts) -> eyre::Result<Self> {
+        if opts.enabled {
+            std::fs::create_dir_all(&opts.dir)
+                .wrap_err_with(|| format!("creating the cache dir {:?}", opts.dir))?;
+        }
+        let manifest = std::fs::read_to_string(opts.dir.join(MANIFEST))
+            .ok()
+            .and_then(|x| serde_json::from_str::<Manifest>(&x).ok())
+            .filter(|x| x.version == VERSION)
+            .unwrap_or_default();
+        Ok(Self {
+            opts: opts.clone(),
+            manifest: Mutex::new(manifest),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn key(input: &str, grammar: &[u8], tool: &str) -> String {
+        let mut h = blake3::Hasher::new();
+        h.update(&VERSION.to_le_bytes());
+        // Length prefixed so that moving bytes from one part to the next changes the hash
+        for part in [input.as_bytes(), grammar, tool.as_bytes()] {
+            h.update(&(part.len() as u64).to_le_bytes());
+            h.update(part);
+        }
+        h.finalize().to_hex().to_string()
+    }
+
+    pub async fn get(&self, key: &str) -> Option<String> {
+        if !self.opts.enabled {
+            return None;
+        }
+        let data = tokio::fs::read(self.opts.dir.join(key)).await.ok()?;
+        // A corrupted entry is just a miss
+        let data = String::from_utf8(data).ok()?;
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.entries.get_mut(key)?.last_used = now();
+        self.dirty.store(true, Ordering::Relaxed);
+        Some(data)
+    }
+
+    pub async fn put(&self, key: &str, data: &str, input: &str, grammar: &Path) {
+        if !self.opts.enabled {
+            return;
+        }
+        let path = self.opts.dir.join(key);
+        if let Err(e) = tokio::fs::write(&path, data).await {
+            warn!("couldn't write to the cache {path:?}: {e}");
+            return;
+        }
+        trace!("perf: wrote to cache");
+
+        let mut manifest = self.manifest.lock().unwrap();
+        let now = now();
+        let entry = Entry {
+            size: data.len() as u64,
+            created: now,
+            last_used: now,
+            input: input.to_owned(),
+            grammar: grammar.to_owned(),
+        };
+        manifest.entries.insert(key.to_owned(), entry);
+        self.evict(&mut manifest, key);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn evict(&self, manifest: &mut Manifest, keep: &str) {
+        let max = self.opts.max_size_mb * 1024 * 1024;
+        let mut size: u64 = manifest.entries.values().map(|x| x.size).sum();
+        while size > max {
+            let Some(oldest) = manifest
+                .entries
+                .iter()
+                .filter(|(k, _)| *k != keep)
+                .min_by_key(|(_, x)| x.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            debug!("evicting {oldest} from the cache");
+            let entry = manifest.entries.remove(&oldest).unwrap();
+            _ = std::fs::remove_file(self.opts.dir.join(&oldest));
+            size -= entry.size;
+        }
+    }
+
+    pub fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let path = self.opts.dir.join(MANIFEST);
+        let manifest = self.manifest.lock().unwrap();
+        let json = serde_json::to_string_pretty(&*manifest).expect("manifest is always valid json");
+        if let Err(e) = std::fs::write(&path, json) {
+            warn!("couldn't write the cache manifest {path:?}: {e}");
+        }
+    }
+
+    pub fn clear(dir: &Path) -> eyre::Result<u64> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Ok(0);
+        };
+        let mut freed = 0;
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            // Entries are named after their hash, don't touch anything else
+            let is_entry = name.chars().all(|x| x.is_ascii_hexdigit());
+            if is_entry || name == MANIFEST {
+                freed += entry.metadata().map(|x| x.len()).unwrap_or(0);
+                std::fs::remove_file(entry.path())
+                    .wrap_err_with(|| format!("removing {:?}", entry.path()))?;
+            }
+        }
+        // Only succeeds if it's empty
+        _ = std::fs::remove_dir(dir);
+        Ok(freed)
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+pub async fn tool_fingerprint(opts: &AnonymizerOpts) -> String {
+    let jar = match tokio::fs::read(&opts.jar).await {
+        Ok(jar) => blake3::hash(&jar).to_hex().to_string(),
+        Err(e) => {
+            warn!("couldn't read {:?} to version the cache: {e}", opts.jar);
+            String::new()
+        }
+    };
+    format!("{jar} {:?} {:?}", opts.command, opts.jvm_opts)
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use super::*;
+
+    fn cache(name: &str, max_size_mb: u64) -> Cache {
+        let dir = std::env::temp_dir().join(format!("cache-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        let opts = CacheOpts {
+            enabled: true,
+            dir,
+            max_size_mb,
+        };
+        Cache::open(&opts).unwrap()
+    }
+
+    #[test]
+    fn keys() {
+        let key = Cache::key("ab", b"c", "tool");
+        assert_eq!(key, Cache::key("ab", b"c", "tool"));
+        assert_ne!(key, Cache::key("a", b"bc", "tool"));
+        assert_ne!(key, Cache::key("ab", b"c", "tool 2"));
+        assert!(key.chars().all(|x| x.is_ascii_hexdigit()));
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let cache = cache("evict", 1);
+        let data = "x".repeat(400 * 1024);
+        let grammar = Path::new("g");
+        cache.put("a", &data, "in", grammar).await;
+        cache.put("b", &data, "in", grammar).await;
+        for (key, time) in [("a", 1), ("b", 2)] {
+            cache
+                .manifest
+                .lock()
+                .unwrap()
+                .entries
+                .get_mut(key)
+                .unwrap()
+                .last_used = time;
+        }
+        assert!(cache.get("a").await.is_some());
+        cache.put("c", &data, "in", grammar).await;
+
+        assert!(cache.get("b").await.is_none());
+        assert!(!cache.opts.dir.join("b").exists());
+        assert!(cache.get("a").await.is_some() && cache.get("c").await.is_some());
+        // Only written once done
+        assert!(!cache.opts.dir.join(MANIFEST).exists());
+        let dir = cache.opts.dir.clone();
+        drop(cache);
+        let manifest: Manifest =
+            serde_json::from_str(&std::fs::read_to_string(dir.join(MANIFEST)).unwrap()).unwrap();
+        assert_eq!(manifest.entries.keys().sorted().collect_vec(), ["a", "c"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clear_only_removes_entries() {
+        let cache = cache("clear", 1);
+        cache.put("ab12", "data", "in", Path::new("g")).await;
+        let dir = cache.opts.dir.clone();
+        drop(cache);
+        std::fs::write(dir.join("notes.txt"), "keep").unwrap();
+
+        let freed = Cache::clear(&dir).unwrap();
+        let manifest = std::fs::metadata(dir.join(MANIFEST));
+        assert!(manifest.is_err());
+        assert!(freed > 4);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,810 @@
+use std::{fmt::Display, ops::Range, str::FromStr};
+
+use serde_json::Value;
+
+use crate::opts::{JsonObj, Message};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataPath {
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Root {
+    Payload,
+    Billingmediation,
+}
+
+impl FromStr for DataPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty path".to_owned());
+        }
+        let mut segments = vec![];
+        for part in s.split('.') {
+            let (key, mut indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+            if !key.is_empty() {
+                segments.push(Segment::Key(key.to_owned()));
+            } else if indices.is_empty() {
+                return Err(format!("empty key in {s}"));
+            }
+            while !indices.is_empty() {
+                let index = indices
+                    .strip_prefix('[')
+                    .and_then(|x| x.split_once(']'))
+                    .and_then(|(index, rest)| Some((index.parse().ok()?, rest)));
+                let Some((index, rest)) = index else {
+                    return Err(format!("bad array index in {s}, expected [<number>]"));
+                };
+                segments.push(Segment::Index(index));
+                indices = rest;
+            }
+        }
+        Ok(Self { segments })
+    }
+}
+
+impl Display for DataPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (n, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Key(key) if n == 0 => write!(f, "{key}")?,
+                Segment::Key(key) => write!(f, ".{key}")?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DataPath {
+    pub fn root(&self) -> (Root, &[Segment]) {
+        match self.segments.split_first() {
+            Some((Segment::Key(key), rest)) if key == "billingmediation" => {
+                (Root::Billingmediation, rest)
+            }
+            _ => (Root::Payload, &self.segments),
+        }
+    }
+
+    /// The value at the path in `msg`. The payload is only parsed as far as needed, and fully only
+    /// to explain why nothing's there.
+    pub fn get(&self, msg: &Message) -> Result<Value, PathError> {
+        match self.root() {
+            (Root::Payload, path) => match msg.inner.payload.get(path) {
+                Some(value) => Ok(value),
+                None => {
+                    let payload = parse(&msg.inner.payload)?;
+                    lookup(&payload, Root::Payload, path).cloned()
+                }
+            },
+            (Root::Billingmediation, path) => {
+                let bm = &msg.inner.billingmediation;
+                let Some(first) = path.first() else {
+                    return Ok(Value::Object(bm.clone()));
+                };
+                let value = match first {
+                    Segment::Key(key) => bm.get(key).ok_or(Miss::Key),
+                    Segment::Index(_) => Err(Miss::Type("object")),
+                };
+                let value = value.map_err(|miss| error(miss, Root::Billingmediation, path, 0))?;
+                walk(value, Root::Billingmediation, path, 1).cloned()
+            }
+        }
+    }
+
+    pub fn set(&self, msg: &mut Message, value: Value) -> Result<(), PathError> {
+        match self.root() {
+            (Root::Payload, path) => {
+                let raw = &msg.inner.payload;
+                // Something's already there, swap its text without parsing the rest
+                if let (false, Ok(Some(span))) = (path.is_empty(), span(raw, path)) {
+                    let payload = format!("{}{value}{}", &raw[..span.start], &raw[span.end..]);
+                    msg.inner.payload = payload.into();
+                    return Ok(());
+                }
+                let mut payload = parse(raw)?;
+                set(&mut payload, Root::Payload, path, value)?;
+                msg.inner.payload = payload.to_string().into();
+                Ok(())
+            }
+            (Root::Billingmediation, path) => {
+                let mut bm = Value::Object(std::mem::take(&mut msg.inner.billingmediation));
+                let ret = set(&mut bm, Root::Billingmediation, path, value);
+                msg.inner.billingmediation = into_object(bm);
+                ret
+            }
+        }
+    }
+
+    pub fn delete(&self, msg: &mut Message) -> Result<Value, PathError> {
+        match self.root() {
+            (Root::Payload, path) => {
+                let mut payload = parse(&msg.inner.payload)?;
+                let ret = delete(&mut payload, Root::Payload, path)?;
+                msg.inner.payload = payload.to_string().into();
+                Ok(ret)
+            }
+            (Root::Billingmediation, path) => {
+                let mut bm = Value::Object(std::mem::take(&mut msg.inner.billingmediation));
+                let ret = delete(&mut bm, Root::Billingmediation, path);
+                msg.inner.billingmediation = into_object(bm);
+                ret
+            }
+        }
+    }
+}
+
+fn parse(payload: &str) -> Result<Value, PathError> {
+    serde_json::from_str(payload).map_err(|e| PathError::Invalid(format!("invalid payload: {e}")))
+}
+
+fn into_object(value: Value) -> JsonObj {
+    match value {
+        Value::Object(obj) => obj,
+        _ => unreachable!("the root is never replaced"),
+    }
+}
+
+pub fn parse_all(paths: &[String]) -> Result<Vec<DataPath>, String> {
+    paths
+        .iter()
+        .map(|x| x.parse().map_err(|e| format!("invalid path {x}: {e}")))
+        .collect()
+}
+
+/// The values at `paths` in `msg`, what messages are grouped by in Aggregation and
+/// Deduplication. A missing value is an error, not a null, so that a mistyped path doesn't put
+/// every message in the same group.
+pub fn key_of(msg: &Message, paths: &[DataPath]) -> Result<Vec<Value>, PathError> {
+    paths.iter().map(|x| x.get(msg)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    MissingKey {
+        at: String,
+        key: String,
+    },
+    OutOfBounds {
+        at: String,
+        index: usize,
+        len: usize,
+    },
+    WrongType {
+        at: String,
+        kind: &'static str,
+        segment: Segment,
+    },
+    Root,
+    Invalid(String),
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::MissingKey { at, key } => write!(f, "{at} has no key {key}"),
+            PathError::OutOfBounds { at, index, len } => {
+                write!(f, "index {index} is out of bounds, {at} has {len} elements")
+            }
+            PathError::WrongType {
+                at,
+                kind,
+                segment: Segment::Key(key),
+            } => write!(f, "{at} is {}, not an object, can't get key {key}", a(kind)),
+            PathError::WrongType {
+                at,
+                kind,
+                segment: Segment::Index(index),
+            } => write!(
+                f,
+                "{at} is {}, not an array, can't get index {index}",
+                a(kind)
+            ),
+            PathError::Root => write!(f, "a path needs at least one key below the root"),
+            PathError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+fn at(root: Root, prefix: &[Segment]) -> String {
+    let path = DataPath {
+        segments: prefix.to_vec(),
+    };
+    match (root, prefix.first()) {
+        (Root::Payload, None) => "the payload".to_owned(),
+        (Root::Payload, Some(_)) => path.to_string(),
+        (Root::Billingmediation, None) => "billingmediation".to_owned(),
+        (Root::Billingmediation, Some(Segment::Key(_))) => format!("billingmediation.{path}"),
+        (Root::Billingmediation, Some(Segment::Index(_))) => format!("billingmediation{path}"),
+    }
+}
+
+enum Miss {
+    Key,
+    Index { len: usize },
+    Type(&'static str),
+}
+
+fn step<'a>(value: &'a Value, segment: &Segment) -> Result<&'a Value, Miss> {
+    match (value, segment) {
+        (Value::Object(obj), Segment::Key(key)) => obj.get(key).ok_or(Miss::Key),
+        (Value::Array(arr), Segment::Index(index)) => {
+            arr.get(*index).ok_or(Miss::Index { len: arr.len() })
+        }
+        (value, _) => Err(Miss::Type(kind(value))),
+    }
+}
+
+fn error(miss: Miss, root: Root, path: &[Segment], n: usize) -> PathError {
+    let at = at(root, &path[..n]);
+    match (miss, &path[n]) {
+        (Miss::Type(kind), segment) => PathError::WrongType {
+            at,
+            kind,
+            segment: segment.clone(),
+        },
+        (Miss::Index { len }, Segment::Index(index)) => PathError::OutOfBounds {
+            at,
+            index: *index,
+            len,
+        },
+        (_, Segment::Key(key)) => PathError::MissingKey {
+            at,
+            key: key.clone(),
+        },
+        (Miss::Key, Segment::Index(_)) => unreachable!(),
+    }
+}
+
+fn step_mut<'a>(
+    value: &'a mut Value,
+    root: Root,
+    path: &[Segment],
+    n: usize,
+    create: bool,
+) -> Result<&'a mut Value, PathError> {
+    if let (Value::Object(obj), Segment::Key(key), true) = (&mut *value, &path[n], create) {
+        obj.entry(key.clone())
+            .or_insert_with(|| Value::Object(Default::default()));
+    }
+    // Checked first so that the error doesn't need `value` borrowed mutably
+    if let Err(miss) = step(value, &path[n]) {
+        return Err(error(miss, root, path, n));
+    }
+    let next = match &path[n] {
+        Segment::Key(key) => value.get_mut(key),
+        Segment::Index(index) => value.get_mut(index),
+    };
+    Ok(next.unwrap())
+}
+
+pub fn lookup<'a>(value: &'a Value, root: Root, path: &[Segment]) -> Result<&'a Value, PathError> {
+    walk(value, root, path, 0)
+}
+
+fn walk<'a>(
+    mut value: &'a Value,
+    root: Root,
+    path: &[Segment],
+    from: usize,
+) -> Result<&'a Value, PathError> {
+    for (n, segment) in path.iter().enumerate().skip(from) {
+        value = step(value, segment).map_err(|miss| error(miss, root, path, n))?;
+    }
+    Ok(value)
+}
+
+pub fn lookup_mut<'a>(
+    value: &'a mut Value,
+    root: Root,
+    path: &[Segment],
+) -> Result<&'a mut Value, PathError> {
+    let mut value = value;
+    for n in 0..path.len() {
+        value = step_mut(value, root, path, n, false)?;
+    }
+    Ok(value)
+}
+
+/// Sets the value at `path` in `value`. Missing objects on the way are created, an index can be
+/// one past the end of its array to append.
+pub fn set(value: &mut Value, root: Root, path: &[Segment], new: Value) -> Result<(), PathError> {
+    let (last, parents) = path.split_last().ok_or(PathError::Root)?;
+    let mut value = value;
+    for n in 0..parents.len() {
+        value = step_mut(value, root, path, n, true)?;
+    }
+    match (value, last) {
+        (Value::Object(obj), Segment::Key(key)) => {
+            obj.insert(key.clone(), new);
+        }
+        (Value::Array(arr), Segment::Index(index)) if *index < arr.len() => arr[*index] = new,
+        (Value::Array(arr), Segment::Index(index)) if *index == arr.len() => arr.push(new),
+        (Value::Array(arr), Segment::Index(_)) => {
+            let len = arr.len();
+            return Err(error(Miss::Index { len }, root, path, parents.len()));
+        }
+        (value, _) => return Err(error(Miss::Type(kind(value)), root, path, parents.len())),
+    }
+    Ok(())
+}
+
+pub fn delete(value: &mut Value, root: Root, path: &[Segment]) -> Result<Value, PathError> {
+    let (last, parents) = path.split_last().ok_or(PathError::Root)?;
+    let parent = lookup_mut(value, root, parents)?;
+    if let Err(miss) = step(parent, last) {
+        return Err(error(miss, root, path, parents.len()));
+    }
+    Ok(match (parent, last) {
+        (Value::Object(obj), Segment::Key(key)) => obj.remove(key).unwrap(),
+        (Value::Array(arr), Segment::Index(index)) => arr.remove(*index),
+        _ => unreachable!(),
+    })
+}
+
+pub fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+pub fn a(kind: &str) -> String {
+    match kind.starts_with(['a', 'e', 'i', 'o', 'u']) {
+        true => format!("an {kind}"),
+        false => format!("a {kind}"),
+    }
+}
+
+/// Where the value at `path` is in `json`, `None` if there's nothing there. The value at the root
+/// is scanned once, nothing is parsed. When a key is there more than once the last one counts,
+/// like with serde_json.
+pub fn span(json: &str, path: &[Segment]) -> Result<Option<Range<usize>>, String> {
+    Scanner::new(json, 0).find(path)
+}
+
+pub fn elements(json: &str, value: Range<usize>) -> Result<Option<Vec<Range<usize>>>, String> {
+    let mut sc = Scanner::new(json, value.start);
+    if sc.peek() != Some(b'[') {
+        return Ok(None);
+    }
+    let mut ret = vec![];
+    sc.array(|sc, _| {
+        ret.push(sc.value()?);
+        Ok(())
+    })?;
+    Ok(Some(ret))
+}
+
+pub type Member = (Range<usize>, Range<usize>);
+
+pub fn members(json: &str) -> Result<Option<Vec<Member>>, String> {
+    let mut sc = Scanner::new(json, 0);
+    if sc.peek() != Some(b'{') {
+        sc.value()?;
+        sc.end()?;
+        return Ok(None);
+    }
+    let mut ret = vec![];
+    sc.object(|sc, key| {
+        ret.push((key, sc.value()?));
+        Ok(())
+    })?;
+    sc.end()?;
+    Ok(Some(ret))
+}
+
+pub fn key_eq(raw: &str, key: &str) -> bool {
+    let inner = &raw[1..raw.len() - 1];
+    if inner.contains('\\') {
+        serde_json::from_str::<String>(raw).is_ok_and(|x| x == key)
+    } else {
+        inner == key
+    }
+}
+
+/// How deep arrays and objects can nest, the same limit as serde_json
+const MAX_DEPTH: usize = 128;
+
+struct Scanner<'a> {
+    json: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(json: &'a str, pos: usize) -> Self {
+        Self {
+            json,
+            pos,
+            depth: 0,
+        }
+    }
+
+    fn error(&self, msg: &str) -> String {
+        format!("invalid json, {msg} at byte {}", self.pos)
+    }
+
+    fn ws(&mut self) {
+        while matches!(self.byte(), Some(b' ' | b'\n' | b'\r' | b'\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn byte(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.pos).copied()
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.ws();
+        self.byte()
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        let ret = self.peek() == Some(b);
+        self.pos += ret as usize;
+        ret
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), String> {
+        match self.eat(b) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected '{}'", b as char))),
+        }
+    }
+
+    fn end(&mut self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.error("trailing characters")),
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.byte().is_some_and(|x| x.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn hex(&mut self) -> Result<u16, String> {
+        match self.json.get(self.pos..self.pos + 4) {
+            Some(hex) if hex.bytes().all(|x| x.is_ascii_hexdigit()) => {
+                self.pos += 4;
+                Ok(u16::from_str_radix(hex, 16).unwrap())
+            }
+            _ => Err(self.error("invalid \\u escape")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Range<usize>, String> {
+        self.ws();
+        let start = self.pos;
+        self.expect(b'"')?;
+        loop {
+            match self.byte() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escape = self.byte();
+                    self.pos += 1;
+                    match escape {
+                        Some(b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => {}
+                        Some(b'u') => match self.hex()? {
+                            0xD800..=0xDBFF => {
+                                let low = match self.json.get(self.pos..self.pos + 2) {
+                                    Some("\\u") => {
+                                        self.pos += 2;
+                                        self.hex()?
+                                    }
+                                    _ => 0,
+                                };
+                                if !(0xDC00..=0xDFFF).contains(&low) {
+                                    return Err(self.error("lone leading surrogate"));
+                                }
+                            }
+                            0xDC00..=0xDFFF => return Err(self.error("lone trailing surrogate")),
+                            _ => {}
+                        },
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                Some(0..=0x1F) => return Err(self.error("control character in string")),
+                Some(_) => self.pos += 1,
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+        self.pos += 1;
+        Ok(start..self.pos)
+    }
+
+    fn number(&mut self) -> Result<(), String> {
+        self.eat(b'-');
+        let leading_zero = self.byte() == Some(b'0');
+        match self.digits() {
+            0 => return Err(self.error("expected a value")),
+            n if leading_zero && n > 1 => return Err(self.error("leading zero")),
+            _ => {}
+        }
+        if self.byte() == Some(b'.') {
+            self.pos += 1;
+            if self.digits() == 0 {
+                return Err(self.error("expected a digit"));
+            }
+        }
+        if matches!(self.byte(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.byte(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return Err(self.error("expected a digit"));
+            }
+        }
+        Ok(())
+    }
+
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Result<(), String>) -> Result<(), String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        self.pos += 1;
+        self.depth += 1;
+        f(self)?;
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn object(
+        &mut self,
+        mut member: impl FnMut(&mut Self, Range<usize>) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.nested(|sc| {
+            if sc.eat(b'}') {
+                return Ok(());
+            }
+            loop {
+                let key = sc.string()?;
+                sc.expect(b':')?;
+                member(sc, key)?;
+                if !sc.eat(b',') {
+                    return sc.expect(b'}');
+                }
+            }
+        })
+    }
+
+    fn array(
+        &mut self,
+        mut element: impl FnMut(&mut Self, usize) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.nested(|sc| {
+            if sc.eat(b']') {
+                return Ok(());
+            }
+            for n in 0.. {
+                element(sc, n)?;
+                if !sc.eat(b',') {
+                    break;
+                }
+            }
+            sc.expect(b']')
+        })
+    }
+
+    fn value(&mut self) -> Result<Range<usize>, String> {
+        let start = match self.peek() {
+            Some(_) => self.pos,
+            None => return Err(self.error("unexpected end")),
+        };
+        match self.byte() {
+            Some(b'{') => self.object(|sc, _| sc.value().map(drop))?,
+            Some(b'[') => self.array(|sc, _| sc.value().map(drop))?,
+            Some(b'"') => {
+                self.string()?;
+            }
+            _ => {
+                let rest = &self.json[self.pos..];
+                match ["true", "false", "null"]
+                    .iter()
+                    .find(|x| rest.starts_with(**x))
+                {
+                    Some(literal) => self.pos += literal.len(),
+                    None => self.number()?,
+                }
+                if self.byte().is_some_and(|x| x.is_ascii_alphanumeric()) {
+                    return Err(self.error("expected a delimiter"));
+                }
+            }
+        }
+        Ok(start..self.pos)
+    }
+
+    fn find(&mut self, path: &[Segment]) -> Result<Option<Range<usize>>, String> {
+        let Some((first, rest)) = path.split_first() else {
+            return self.value().map(Some);
+        };
+        let mut found = None;
+        match (first, self.peek()) {
+            (Segment::Key(key), Some(b'{')) => self.object(|sc, k| {
+                if key_eq(&sc.json[k], key) {
+                    found = sc.find(rest)?;
+                } else {
+                    sc.value()?;
+                }
+                Ok(())
+            })?,
+            (Segment::Index(index), Some(b'[')) => self.array(|sc, n| {
+                if n == *index {
+                    found = sc.find(rest)?;
+                } else {
+                    sc.value()?;
+                }
+                Ok(())
+            })?,
+            _ => {
+                self.value()?;
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opts::MessageInner;
+
+    fn msg(payload: &str) -> Message {
+        Message {
+            inner: MessageInner::from(payload.to_owned()),
+            date: Default::default(),
+            source: None,
+        }
+    }
+
+    fn path(s: &str) -> DataPath {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn get_set_delete() {
+        let mut msg = msg(r#"{"a": [{"b": 1}, {"b": 2}], "s": "x"}"#);
+        assert_eq!(path("a[1].b").get(&msg), Ok(Value::from(2)));
+
+        let errors = [
+            ("a[0].c", "a[0] has no key c"),
+            ("a[5].b", "index 5 is out of bounds, a has 2 elements"),
+            ("s.t", "s is a string, not an object, can't get key t"),
+            ("a.b", "a is an array, not an object, can't get key b"),
+            ("billingmediation.x", "billingmediation has no key x"),
+        ];
+        for (p, e) in errors {
+            assert_eq!(path(p).get(&msg).unwrap_err().to_string(), e);
+        }
+
+        path("a[1].b").set(&mut msg, Value::from(3)).unwrap();
+        path("a[2]").set(&mut msg, Value::from(4)).unwrap();
+        path("new.deep").set(&mut msg, Value::from(true)).unwrap();
+        path("billingmediation.x.y")
+            .set(&mut msg, Value::from("z"))
+            .unwrap();
+        assert_eq!(
+            path("a[0]").delete(&mut msg),
+            Ok(serde_json::json!({"b": 1}))
+        );
+        assert_eq!(path("s").delete(&mut msg), Ok(Value::from("x")));
+        assert!(path("s").delete(&mut msg).is_err());
+        assert_eq!(
+            msg.to_json(),
+            *serde_json::json!({
+                "a": [{"b": 3}, 4],
+                "new": {"deep": true},
+                "billingmediation": {"x": {"y": "z"}},
+            })
+            .as_object()
+            .unwrap()
+        );
+        assert_eq!(
+            path("billingmediation").delete(&mut msg),
+            Err(PathError::Root)
+        );
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        let valid = [
+            "0",
+            "-0.5",
+            "1e3",
+            "2.5E-7",
+            "true",
+            "null",
+            r#""a\"é😀""#,
+            r#"{"a": [1, false, {}], "b": "\n"}"#,
+        ];
+        for json in valid {
+            assert!(serde_json::from_str::<Value>(json).is_ok(), "{json}");
+            assert_eq!(span(json, &[]), Ok(Some(0..json.len())), "{json}");
+        }
+        let invalid = [
+            "tru",
+            "nul",
+            "truex",
+            "abc",
+            "01",
+            "1.",
+            ".5",
+            "1e",
+            "-",
+            "+1",
+            "0x10",
+            "1.5e+",
+            r#""\x""#,
+            r#""\u12g4""#,
+            r#""\ud83d""#,
+            r#""\ude00""#,
+            "\"\t\"",
+            "[1 2]",
+            r#"{"a" 1}"#,
+            "{'a': 1}",
+        ];
+        for json in invalid {
+            assert!(serde_json::from_str::<Value>(json).is_err(), "{json}");
+            assert!(span(json, &[]).is_err(), "{json}");
+        }
+        assert!(members(r#"{"a": 1} x"#).is_err());
+    }
+
+    #[test]
+    fn last_duplicate_key_wins() {
+        let json = r#"{"a": {"b": 1}, "c": 2, "a": {"b": 3}}"#;
+        let parsed: Value = serde_json::from_str(json).unwrap();
+        let span = span(json, &path("a.b").segments).unwrap().unwrap();
+        assert_eq!(parsed["a"]["b"], Value::from(3));
+        assert_eq!(&json[span], "3");
+
+        let mut msg = msg(json);
+        assert_eq!(path("a.b").get(&msg), Ok(Value::from(3)));
+        path("a.b").set(&mut msg, Value::from(4)).unwrap();
+        assert_eq!(msg.to_json()["a"], serde_json::json!({"b": 4}));
+    }
+
+    #[test]
+    fn values_are_scanned_once() {
+        // A single pass over the root finds the value, however deep
+        let n = MAX_DEPTH - 1;
+        let json = format!("{}1{}", r#"{"a":"#.repeat(n), "}".repeat(n));
+        let path = vec![Segment::Key("a".to_owned()); n];
+        let mut sc = Scanner::new(&json, 0);
+        assert_eq!(sc.find(&path).unwrap(), Some(5 * n..5 * n + 1));
+        assert_eq!(sc.pos, json.len());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let deep = |n| format!(r#"{{"a": {}1{}}}"#, "[".repeat(n), "]".repeat(n));
+        assert!(span(&deep(MAX_DEPTH - 1), &[]).is_ok());
+        let e = span(&deep(100_000), &[]).unwrap_err();
+        assert_eq!(
+            e,
+            format!("invalid json, too deeply nested at byte {}", 5 + MAX_DEPTH)
+        );
+    }
+}
@@ -0,0 +1,665 @@
+use std::{cmp::Ordering, fmt::Display, str::FromStr, sync::LazyLock};
+
+use jiff::{fmt::temporal::Pieces, tz::Offset, Timestamp};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+pub type Civil = jiff::civil::DateTime;
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+const DAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone(jiff::tz::TimeZone);
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone(jiff::tz::TimeZone::UTC);
+
+    pub fn fixed(offset: i32) -> Result<Self, String> {
+        let offset = Offset::from_seconds(offset).map_err(|e| e.to_string())?;
+        Ok(Self(jiff::tz::TimeZone::fixed(offset)))
+    }
+
+    /// Like Java, a time that happens twice when clocks go back is the first one, and a time
+    /// skipped when they go forward is moved forward by the length of the gap.
+    fn instant_of(&self, local: Civil) -> Result<Timestamp, String> {
+        let ambiguous = self.0.to_ambiguous_timestamp(local);
+        ambiguous.compatible().map_err(|e| e.to_string())
+    }
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::UTC
+    }
+}
+
+impl FromStr for TimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let offset = s
+            .strip_prefix("UTC")
+            .or_else(|| s.strip_prefix("GMT"))
+            .unwrap_or(s);
+        if matches!(offset, "" | "Z") {
+            return Ok(TimeZone::UTC);
+        }
+        match parse_offset(offset) {
+            Some((0, len)) if len == offset.len() => Ok(TimeZone::UTC),
+            Some((x, len)) if len == offset.len() => TimeZone::fixed(x),
+            _ => jiff::tz::TimeZone::get(s).map(Self).map_err(|_| {
+                format!(
+                    "unknown time zone {s}, use UTC, an offset like +01:00 or a zone of the tz \
+                     database like Europe/Paris"
+                )
+            }),
+        }
+    }
+}
+
+impl Display for TimeZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.0.iana_name(), self.0.to_fixed_offset()) {
+            (Some(name), _) => write!(f, "{name}"),
+            (None, Ok(offset)) => write!(f, "{}", format_offset(offset.seconds(), true)),
+            (None, Err(_)) => write!(f, "{:?}", self.0),
+        }
+    }
+}
+
+impl Serialize for TimeZone {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeZone {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn parse_offset(s: &str) -> Option<(i32, usize)> {
+    let sign = match s.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits = |at: usize| {
+        let x = s.get(at..at + 2)?;
+        x.bytes()
+            .all(|x| x.is_ascii_digit())
+            .then(|| x.parse::<i32>().unwrap())
+    };
+    let hours = digits(1)?;
+    let (minutes, len) = match s.as_bytes().get(3) {
+        Some(b':') => (digits(4)?, 6),
+        Some(b'0'..=b'9') => (digits(3)?, 5),
+        _ => (0, 3),
+    };
+    if hours > 18 || minutes > 59 {
+        return None;
+    }
+    Some((sign * (hours * 3600 + minutes * 60), len))
+}
+
+fn format_offset(offset: i32, colon: bool) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let (hours, minutes) = (offset.abs() / 3600, offset.abs() / 60 % 60);
+    match colon {
+        true => format!("{sign}{hours:02}:{minutes:02}"),
+        false => format!("{sign}{hours:02}{minutes:02}"),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DateTime {
+    instant: Timestamp,
+    offset: Offset,
+}
+
+impl DateTime {
+    pub fn from_local(local: Civil, zone: &TimeZone) -> Result<Self, String> {
+        Ok(Self::from_instant(zone.instant_of(local)?, zone))
+    }
+
+    fn with_offset(local: Civil, offset: Offset) -> Result<Self, String> {
+        let instant = offset.to_timestamp(local).map_err(|e| e.to_string())?;
+        Ok(Self { instant, offset })
+    }
+
+    fn from_instant(instant: Timestamp, zone: &TimeZone) -> Self {
+        Self {
+            instant,
+            offset: zone.0.to_offset(instant),
+        }
+    }
+
+    pub fn local(&self) -> Civil {
+        self.offset.to_datetime(self.instant)
+    }
+
+    pub fn in_zone(&self, zone: &TimeZone) -> Self {
+        Self::from_instant(self.instant, zone)
+    }
+
+    pub fn from_timestamp(secs: i64, zone: &TimeZone) -> Self {
+        let instant = match Timestamp::from_second(secs) {
+            Ok(instant) => instant,
+            Err(_) if secs < 0 => Timestamp::MIN,
+            Err(_) => Timestamp::MAX,
+        };
+        Self::from_instant(instant, zone)
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.instant.as_second()
+    }
+
+    pub fn add_seconds(&self, secs: i64) -> Self {
+        let instant = match self
+            .instant
+            .checked_add(jiff::SignedDuration::from_secs(secs))
+        {
+            Ok(instant) => instant,
+            Err(_) if secs < 0 => Timestamp::MIN,
+            Err(_) => Timestamp::MAX,
+        };
+        Self { instant, ..*self }
+    }
+
+    /// Parses the `date` and `date-time` formats of json schema: `2024-04-01`, which is midnight
+    /// in `zone`, or `2024-04-01T00:09:22Z`. The offset and the seconds of a date-time can be
+    /// left out, then it's in `zone`, or in the zone of a `[Europe/Paris]` suffix.
+    pub fn parse_iso(s: &str, zone: &TimeZone) -> Result<Self, String> {
+        let err = |e: &dyn Display| {
+            format!("invalid date {s}, expected 2024-04-01 or 2024-04-01T00:09:22Z: {e}")
+        };
+        let pieces = Pieces::parse(s).map_err(|e| err(&e))?;
+        let local = pieces.date().to_datetime(pieces.time().unwrap_or_default());
+        if let Some(offset) = pieces.to_numeric_offset() {
+            return Self::with_offset(local, offset).map_err(|e| err(&e));
+        }
+        match pieces.to_time_zone().map_err(|e| err(&e))? {
+            Some(named) => Self::from_local(local, &TimeZone(named)),
+            None => Self::from_local(local, zone),
+        }
+        .map_err(|e| err(&e))
+    }
+}
+
+impl PartialEq for DateTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DateTime {}
+
+impl PartialOrd for DateTime {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DateTime {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.instant.cmp(&other.instant)
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.local())?;
+        match self.offset.seconds() {
+            0 => write!(f, "Z"),
+            offset => write!(f, "{}", format_offset(offset, true)),
+        }
+    }
+}
+
+static PERIOD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^([-+]?)P(?:([-+]?[0-9]+)D)?(T(?:([-+]?[0-9]+)H)?(?:([-+]?[0-9]+)M)?(?:([-+]?[0-9]+)(?:[.,]([0-9]{0,9}))?S)?)?$",
+    )
+    .unwrap()
+});
+
+/// Parses the `period`s of the playbooks, ISO 8601 durations like `P1D`, `PT1H30M` or `-PT15M`,
+/// into seconds. Like Java's `Duration`, a day is 24 hours and every part can have a sign.
+pub fn parse_period(s: &str) -> Result<i64, String> {
+    let err = || format!("invalid period {s}, expected an ISO 8601 duration like P1D or PT1H");
+    let caps = PERIOD.captures(s).ok_or_else(err)?;
+    // `P` and `PT` alone are invalid
+    let parts = [(2, 86400), (4, 3600), (5, 60), (6, 1)];
+    if parts.iter().all(|&(i, _)| caps.get(i).is_none()) {
+        return Err(err());
+    }
+    if caps
+        .get(7)
+        .is_some_and(|x| x.as_str().bytes().any(|x| x != b'0'))
+    {
+        return Err(format!(
+            "invalid period {s}, fractions of a second aren't supported"
+        ));
+    }
+    let mut secs: i64 = 0;
+    for (i, unit) in parts {
+        if let Some(x) = caps.get(i) {
+            let n = x.as_str().parse::<i64>().map_err(|_| err())?;
+            secs = n
+                .checked_mul(unit)
+                .and_then(|x| secs.checked_add(x))
+                .ok_or_else(err)?;
+        }
+    }
+    Ok(if &caps[1] == "-" { -secs } else { secs })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Literal(String),
+    Field(char, usize),
+}
+
+/// A Java `DateTimeFormatter` pattern. The supported letters are `y`/`u` (year), `M` (month, as
+/// a number, `MMM` for `Jan` or `MMMM` for `January`), `d`, `H`, `h` with `a` (AM/PM), `m`,
+/// `s`, `S` (fraction of a second), `E` (day of the week, ignored when parsing), `X`/`x`/`Z`
+/// (offset) and `VV` (zone). Text between single quotes is literal, as is anything that isn't a
+/// letter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatePattern {
+    items: Vec<Item>,
+}
+
+impl FromStr for DatePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut items: Vec<Item> = vec![];
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            let item = match c {
+                '\'' => {
+                    let mut literal = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\'') if chars.peek() == Some(&'\'') => {
+                                chars.next();
+                                literal.push('\'');
+                            }
+                            Some('\'') => break,
+                            Some(c) => literal.push(c),
+                            None => return Err(format!("unterminated quote in {s}")),
+                        }
+                    }
+                    // '' is a quote
+                    if literal.is_empty() {
+                        literal.push('\'');
+                    }
+                    Item::Literal(literal)
+                }
+                'y' | 'u' | 'M' | 'd' | 'H' | 'h' | 'a' | 'm' | 's' | 'S' | 'E' | 'X' | 'x'
+                | 'Z' | 'V' => {
+                    let mut count = 1;
+                    while chars.next_if_eq(&c).is_some() {
+                        count += 1;
+                    }
+                    let ok = match c {
+                        'M' | 'E' => count <= 4,
+                        'd' | 'H' | 'h' | 'm' | 's' => count <= 2,
+                        'a' => count == 1,
+                        'X' | 'x' => count <= 3,
+                        'Z' => count <= 3 || count == 5,
+                        'V' => count == 2,
+                        'S' => count <= 9,
+                        _ => true,
+                    };
+                    if !ok {
+                        let field = c.to_string().repeat(count);
+                        return Err(format!("unsupported field {field} in {s}"));
+                    }
+                    Item::Field(c, count)
+                }
+                c if c.is_ascii_alphabetic() => {
+                    return Err(format!("unsupported pattern letter '{c}' in {s}"));
+                }
+                c => Item::Literal(c.to_string()),
+            };
+            // Consecutive literals are merged, they're matched as a whole
+            match (items.last_mut(), item) {
+                (Some(Item::Literal(last)), Item::Literal(x)) => last.push_str(&x),
+                (_, item) => items.push(item),
+            }
+        }
+        if items.is_empty() {
+            return Err("empty date pattern".to_owned());
+        }
+        Ok(Self { items })
+    }
+}
+
+fn is_numeric(item: Option<&Item>) -> bool {
+    matches!(item, Some(Item::Field(c, n)) if "yuMdHhmsS".contains(*c) && (*c != 'M' || *n <= 2))
+}
+
+fn parse_name(s: &str, names: &[&str]) -> Option<(usize, usize)> {
+    names.iter().enumerate().find_map(|(n, name)| {
+        [name, &name[..3]]
+            .into_iter()
+            .find(|x| s.get(..x.len()).is_some_and(|s| s.eq_ignore_ascii_case(x)))
+            .map(|x| (n, x.len()))
+    })
+}
+
+impl DatePattern {
+    /// Reads `s`, which has to match the whole pattern. The year, month and day are required, the
+    /// time defaults to midnight, and the zone to `zone` when the pattern has no offset.
+    pub fn parse(&self, s: &str, zone: &TimeZone) -> Result<DateTime, String> {
+        let mut rest = s;
+        let (mut year, mut month, mut day) = (None, None, None);
+        let (mut hour, mut minute, mut second, mut nano) = (0, 0, 0, 0);
+        let mut offset = None;
+        let mut in_zone = None;
+        let mut pm = None;
+        let err = |what: &str| format!("{s} doesn't match the date pattern, {what}");
+        for (n, item) in self.items.iter().enumerate() {
+            let (c, count) = match item {
+                Item::Literal(literal) => {
+                    rest = rest
+                        .strip_prefix(literal.as_str())
+                        .ok_or_else(|| err(&format!("expected '{literal}'")))?;
+                    continue;
+                }
+                Item::Field(c, count) => (*c, *count),
+            };
+            if is_numeric(Some(item)) {
+                let (min, max) = match c {
+                    'y' | 'u' if count == 2 => (2, 2),
+                    'y' | 'u' => (count, 9),
+                    'S' => (count, count),
+                    _ => (count, 2),
+                };
+                // Fields without separators in between, like yyyyMMdd, have a fixed width
+                let max = match is_numeric(self.items.get(n + 1)) {
+                    true => min.max(2).min(max),
+                    false => max,
+                };
+                let len = rest
+                    .bytes()
+                    .take(max)
+                    .take_while(u8::is_ascii_digit)
+                    .count();
+                if len < min {
+                    return Err(err(&format!("expected {min} digits")));
+                }
+                let value: u32 = rest[..len].parse().unwrap();
+                rest = &rest[len..];
+                match c {
+                    'y' | 'u' if count == 2 => year = Some(2000 + value),
+                    'y' | 'u' => year = Some(value),
+                    'M' => month = Some(value),
+                    'd' => day = Some(value),
+                    'H' => hour = value,
+                    'h' if !(1..=12).contains(&value) => {
+                        return Err(err(&format!("{value} isn't an hour from 1 to 12")))
+                    }
+                    'h' => hour = value % 12,
+                    'm' => minute = value,
+                    's' => second = value,
+                    'S' => nano = value * 10u32.pow(9 - count as u32),
+                    _ => unreachable!(),
+                }
+                continue;
+            }
+            match c {
+                'M' => {
+                    let (n, len) =
+                        parse_name(rest, &MONTHS).ok_or_else(|| err("expected a month"))?;
+                    month = Some(n as u32 + 1);
+                    rest = &rest[len..];
+                }
+                'E' => {
+                    let (_, len) = parse_name(rest, &DAYS).ok_or_else(|| err("expected a day"))?;
+                    rest = &rest[len..];
+                }
+                'a' => {
+                    let am_pm = rest.get(..2).map(|x| x.to_ascii_uppercase());
+                    pm = Some(match am_pm.as_deref() {
+                        Some("AM") => false,
+                        Some("PM") => true,
+                        _ => return Err(err("expected AM or PM")),
+                    });
+                    rest = &rest[2..];
+                }
+                'X' | 'Z' if rest.starts_with('Z') => {
+                    offset = Some(0);
+                    rest = &rest[1..];
+                }
+                'X' | 'x' | 'Z' => {
+                    let (x, len) = parse_offset(rest).ok_or_else(|| err("expected an offset"))?;
+                    offset = Some(x);
+                    rest = &rest[len..];
+                }
+                'V' => {
+                    let len = rest
+                        .find(|x: char| !(x.is_ascii_alphanumeric() || "/_+-:".contains(x)))
+                        .unwrap_or(rest.len());
+                    in_zone = Some(rest[..len].parse::<TimeZone>().map_err(|e| err(&e))?);
+                    rest = &rest[len..];
+                }
+                _ => unreachable!(),
+            }
+        }
+        if !rest.is_empty() {
+            return Err(err(&format!("unexpected {rest}")));
+        }
+        if pm == Some(true) {
+            hour += 12;
+        }
+        let (Some(year), Some(month), Some(day)) = (year, month, day) else {
+            return Err(format!(
+                "{s}: the date pattern needs a year, a month and a day"
+            ));
+        };
+        let field = |x: u32| x.try_into().unwrap_or(i8::MAX);
+        let local = Civil::new(
+            year.try_into().unwrap_or(i16::MAX),
+            field(month),
+            field(day),
+            field(hour),
+            field(minute),
+            field(second),
+            nano as i32,
+        )
+        .map_err(|e| format!("{s}: {e}"))?;
+        match offset {
+            Some(offset) => {
+                let offset = Offset::from_seconds(offset).map_err(|e| e.to_string())?;
+                DateTime::with_offset(local, offset)
+            }
+            None => DateTime::from_local(local, in_zone.as_ref().unwrap_or(zone)),
+        }
+        .map_err(|e| format!("{s}: {e}"))
+    }
+
+    pub fn format(&self, date: &DateTime) -> String {
+        let l = date.local();
+        let offset = date.offset.seconds();
+        let mut ret = String::new();
+        for item in &self.items {
+            let (c, count) = match item {
+                Item::Literal(literal) => {
+                    ret.push_str(literal);
+                    continue;
+                }
+                Item::Field(c, count) => (*c, *count),
+            };
+            let number = |x: i8| format!("{x:0count$}");
+            let month = l.month() as usize - 1;
+            let weekday = l.weekday().to_monday_zero_offset() as usize;
+            let field = match c {
+                'y' | 'u' if count == 2 => format!("{:02}", l.year().rem_euclid(100)),
+                'y' | 'u' => format!("{:0count$}", l.year()),
+                'M' if count == 3 => MONTHS[month][..3].to_owned(),
+                'M' if count == 4 => MONTHS[month].to_owned(),
+                'M' => number(l.month()),
+                'E' if count == 4 => DAYS[weekday].to_owned(),
+                'E' => DAYS[weekday][..3].to_owned(),
+                'd' => number(l.day()),
+                'H' => number(l.hour()),
+                'h' => number((l.hour() + 11) % 12 + 1),
+                'a' => if l.hour() < 12 { "AM" } else { "PM" }.to_owned(),
+                'm' => number(l.minute()),
+                's' => number(l.second()),
+                'S' => format!("{:09}", l.subsec_nanosecond())[..count].to_owned(),
+                'X' if offset == 0 => "Z".to_owned(),
+                'Z' if count == 5 && offset == 0 => "Z".to_owned(),
+                'X' | 'x' if count == 1 => format_offset(offset, false)[..3].to_owned(),
+                'X' | 'x' if count == 3 => format_offset(offset, true),
+                'Z' if count == 5 => format_offset(offset, true),
+                'X' | 'x' | 'Z' => format_offset(offset, false),
+                'V' => match offset {
+                    0 => "Z".to_owned(),
+                    offset => format_offset(offset, true),
+                },
+                _ => unreachable!(),
+            };
+            ret.push_str(&field);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn iso(s: &str) -> DateTime {
+        DateTime::parse_iso(s, &TimeZone::UTC).unwrap()
+    }
+
+    #[test]
+    fn patterns() {
+        let paris: TimeZone = "Europe/Paris".parse().unwrap();
+        let cases = [
+            ("yyyyMMdd HHmm", "20240401 0009", "2024-03-31T22:09:00Z"),
+            ("dd/MM/yy", "15/01/24", "2024-01-14T23:00:00Z"),
+            (
+                "yyyy-MM-dd'T'HH:mm:ss.SSSXXX",
+                "2024-04-01T00:09:22.500Z",
+                "2024-04-01T00:09:22.5Z",
+            ),
+            (
+                "yyyyMMddHHmmssZ",
+                "20241027023000+0100",
+                "2024-10-27T01:30:00Z",
+            ),
+            (
+                "d MMM yyyy h:mm a",
+                "3 Feb 2024 1:05 PM",
+                "2024-02-03T12:05:00Z",
+            ),
+            (
+                "EEE, dd MMMM yyyy '['VV']'",
+                "Sat, 03 February 2024 [+05:30]",
+                "2024-02-02T18:30:00Z",
+            ),
+        ];
+        for (pattern, s, utc) in cases {
+            let pattern: DatePattern = pattern.parse().unwrap();
+            let date = pattern.parse(s, &paris).unwrap();
+            assert_eq!(date.in_zone(&TimeZone::UTC).to_string(), utc);
+            assert_eq!(pattern.format(&date), s);
+        }
+
+        let pattern: DatePattern = "yyyyMMdd".parse().unwrap();
+        assert!(pattern.parse("20240230", &paris).is_err());
+        assert!(pattern.parse("2024011", &paris).is_err());
+        let pattern: DatePattern = "yyyyMMdd hh:mm a".parse().unwrap();
+        assert!(pattern.parse("20240101 12:00 AM", &paris).is_ok());
+        assert!(pattern.parse("20240101 13:00 PM", &paris).is_err());
+        assert!(pattern.parse("20240101 00:00 AM", &paris).is_err());
+        assert!("yyyy-MM-dd QQ".parse::<DatePattern>().is_err());
+        assert!("'unterminated".parse::<DatePattern>().is_err());
+    }
+
+    #[test]
+    fn periods() {
+        assert_eq!(parse_period("PT1H30M"), Ok(5400));
+        assert_eq!(parse_period("-PT15M"), Ok(-900));
+        assert_eq!(parse_period("P1DT-30M"), Ok(84600));
+        assert_eq!(parse_period("PT10.000S"), Ok(10));
+        for invalid in ["P", "PT", "PT1.5S", "1D", "P1W"] {
+            assert!(parse_period(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn zones() {
+        let paris: TimeZone = "Europe/Paris".parse().unwrap();
+        let local = |s| DateTime::parse_iso(s, &paris).unwrap().to_string();
+        assert_eq!(local("2024-01-15"), "2024-01-15T00:00:00+01:00");
+        assert_eq!(local("2024-07-15T12:00"), "2024-07-15T12:00:00+02:00");
+        // Skipped when clocks go forward, and twice when they go back
+        assert_eq!(local("2024-03-31T02:30"), "2024-03-31T03:30:00+02:00");
+        assert_eq!(local("2024-10-27T02:30"), "2024-10-27T02:30:00+02:00");
+        assert_eq!(
+            iso("2024-10-27T01:30:00Z").in_zone(&paris).to_string(),
+            "2024-10-27T02:30:00+01:00"
+        );
+
+        // Any zone of the tz database
+        let new_york: TimeZone = "America/New_York".parse().unwrap();
+        assert_eq!(
+            DateTime::parse_iso("2024-03-10T02:30", &new_york)
+                .unwrap()
+                .to_string(),
+            "2024-03-10T03:30:00-04:00"
+        );
+        assert_eq!(
+            iso("2024-07-01T12:00:00+05:30[Asia/Kolkata]").to_string(),
+            "2024-07-01T12:00:00+05:30"
+        );
+        assert_eq!(
+            iso("2024-07-01T12:00[Asia/Kolkata]").to_string(),
+            "2024-07-01T12:00:00+05:30"
+        );
+
+        assert_eq!("+01:00".parse(), TimeZone::fixed(3600));
+        assert_eq!("UTC-0530".parse(), TimeZone::fixed(-19800));
+        assert_eq!("GMT".parse(), Ok(TimeZone::UTC));
+        assert_eq!(new_york.to_string(), "America/New_York");
+        assert_eq!(TimeZone::fixed(-19800).unwrap().to_string(), "-05:30");
+        assert!("Mars/Olympus".parse::<TimeZone>().is_err());
+
+        assert!(iso("2024-04-01T02:00:00+02:00") == iso("2024-04-01T00:00:00Z"));
+        assert!(iso("2024-02-29") < iso("2024-03-01T00:00:00.000000001Z"));
+        assert!(DateTime::parse_iso("2023-02-29", &TimeZone::UTC).is_err());
+        assert!(DateTime::parse_iso("2024-04-01T25:00", &TimeZone::UTC).is_err());
+    }
+}
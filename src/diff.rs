@@ -0,0 +1,151 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use eyre::Context;
+use itertools::{EitherOrBoth, Itertools};
+
+use crate::output::read_records;
+
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub name: String,
+    pub left: Option<usize>,
+    pub right: Option<usize>,
+    pub differing: Vec<usize>,
+}
+
+impl FileDiff {
+    pub fn is_same(&self) -> bool {
+        self.left == self.right && self.differing.is_empty()
+    }
+}
+
+impl Display for FileDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = |x: Option<usize>| x.map_or("missing".to_owned(), |x| x.to_string());
+        write!(
+            f,
+            "{}: {} vs {} messages",
+            self.name,
+            count(self.left),
+            count(self.right)
+        )?;
+        if !self.differing.is_empty() {
+            let shown = self.differing.iter().take(10).join(", ");
+            let more = if self.differing.len() > 10 {
+                ", ..."
+            } else {
+                ""
+            };
+            write!(
+                f,
+                ", {} differ (messages {shown}{more})",
+                self.differing.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn diff_paths(a: &Path, b: &Path) -> eyre::Result<Vec<FileDiff>> {
+    if !a.is_dir() || !b.is_dir() {
+        let name = a.file_name().unwrap_or_default().to_string_lossy().into();
+        return Ok(vec![diff_files(name, Some(a), Some(b))?]);
+    }
+
+    let names = |dir: &Path| -> eyre::Result<BTreeSet<String>> {
+        let entries = std::fs::read_dir(dir).wrap_err_with(|| format!("reading {dir:?}"))?;
+        Ok(entries
+            .filter_map(Result::ok)
+            .filter(|x| x.path().is_file())
+            .map(|x| x.file_name().to_string_lossy().into_owned())
+            .filter(|x| !x.starts_with('_'))
+            .collect())
+    };
+    let (left, right) = (names(a)?, names(b)?);
+
+    left.union(&right)
+        .map(|name| {
+            let side =
+                |dir: &Path, names: &BTreeSet<String>| names.contains(name).then(|| dir.join(name));
+            diff_files(
+                name.clone(),
+                side(a, &left).as_deref(),
+                side(b, &right).as_deref(),
+            )
+        })
+        .collect()
+}
+
+fn diff_files(name: String, a: Option<&Path>, b: Option<&Path>) -> eyre::Result<FileDiff> {
+    let read = |path: Option<&Path>| -> eyre::Result<Option<Vec<String>>> {
+        let Some(path) = path else { return Ok(None) };
+        let records = read_records(path).wrap_err_with(|| format!("reading {path:?}"))?;
+        Ok(Some(records))
+    };
+    let (left, right) = (read(a)?, read(b)?);
+
+    let differing = match (&left, &right) {
+        (Some(left), Some(right)) => left
+            .iter()
+            .zip_longest(right)
+            .enumerate()
+            .filter_map(|(n, x)| match x {
+                EitherOrBoth::Both(a, b) if same_message(a, b) => None,
+                _ => Some(n + 1),
+            })
+            .collect(),
+        _ => vec![],
+    };
+
+    Ok(FileDiff {
+        name,
+        left: left.map(|x| x.len()),
+        right: right.map(|x| x.len()),
+        differing,
+    })
+}
+
+/// Messages are compared as json so that key order doesn't matter
+fn same_message(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    match (
+        serde_json::from_str::<serde_json::Value>(a),
+        serde_json::from_str::<serde_json::Value>(b),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn compares_records_across_formats() {
+        let dir = std::env::temp_dir().join(format!("diff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("split.json");
+        std::fs::write(&json, "[\n  {\"a\": 1, \"b\": 2},\n  {\"a\": 3}\n]").unwrap();
+        let gz = dir.join("split.jsonl.gz");
+        let mut enc = GzEncoder::new(std::fs::File::create(&gz).unwrap(), Compression::default());
+        enc.write_all(b"{\"b\":2,\"a\":1}\n{\"a\":4}\n{\"a\":5}\n")
+            .unwrap();
+        enc.finish().unwrap();
+
+        let diff = &diff_paths(&json, &gz).unwrap()[0];
+        assert_eq!((diff.left, diff.right), (Some(2), Some(3)));
+        assert_eq!(diff.differing, [2, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,7 +2,7 @@
 //!
 
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
     thread::sleep,
     time::{Duration, Instant},
@@ -10,9 +10,23 @@ use std::{
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use crossbeam::thread;
+use eyre::{Context, ContextCompat};
+use itertools::Itertools;
 use serde::de::IntoDeserializer;
 
-use crate::{execution::execute_playbook, ingestion, js, opts::Options, playbook::Playbook, tree};
+use crate::{
+    datetime::DateTime,
+    execution::execute_playbook,
+    ingestion, js,
+    opts::{InputMetadata, Options, OutputFormat},
+    output::{self, OutputOpts},
+    playbook::{Module, Playbook},
+    reporting::Clock,
+    summary::RunSummary,
+    tree,
+};
+
+const INGESTION_CAP: usize = 1024;
 
 #[derive(Debug, Clone)]
 pub struct Benchmarker {
@@ -20,26 +34,213 @@ pub struct Benchmarker {
     last: Instant,
 }
 
+impl Default for Benchmarker {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self { t0: now, last: now }
+    }
+}
+
+impl Benchmarker {
+    pub fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let ret = now - self.last;
+        self.last = now;
+        ret
+    }
+
+    pub fn total(&self) -> Duration {
+        self.t0.elapsed()
+    }
+}
+
 #[instrument]
-pub async fn entry(opts: Options) {
-    let pb = Playbook::new(opts.playbook_file_path);
+pub async fn entry(mut opts: Options) -> eyre::Result<()> {
+    let bench = Benchmarker::default();
+    std::fs::create_dir_all(&opts.output_dir)
+        .wrap_err_with(|| format!("creating the output dir {:?}", opts.output_dir))?;
+    // Written first so that a failed run can be replayed too
+    let config = serde_json::to_string_pretty(&opts).unwrap();
+    std::fs::write(opts.output_dir.join("_config.json"), config)
+        .wrap_err("writing the config of the run")?;
+    let zone = opts.time_zone;
+    let period = match &opts.period {
+        Some(period) => {
+            let date = |x| DateTime::parse_iso(x, &zone);
+            let (start, end) = (date(&period.start), date(&period.end));
+            let (start, end) = start
+                .and_then(|start| Ok((start, end?)))
+                .map_err(|e| eyre::eyre!("invalid period: {e}"))?;
+            if start >= end {
+                eyre::bail!("invalid period: {start} is not before {end}");
+            }
+            Some((start, end))
+        }
+        None => None,
+    };
+    let output = OutputOpts {
+        dir: opts.output_dir.clone(),
+        format: opts.output_format,
+        compression: opts.output_compression,
+        reports_dir: opts
+            .reports_dir
+            .as_ref()
+            .map_or(opts.output_dir.join("reports"), PathBuf::from),
+        clock: Clock {
+            zone: zone.clone(),
+            period,
+        },
+    };
+    // Inputs aren't read when starting from a saved output
+    if opts.start_at.is_none() {
+        opts.input = ingestion::expand_inputs(std::mem::take(&mut opts.input))?;
+    }
+    if let Some(date) = &opts.process_date {
+        for input in &mut opts.input {
+            input.metadata = Some(InputMetadata {
+                process_date: date.clone(),
+            });
+        }
+    }
+    // Written in RFC 3339 once and for all, so that everything downstream reads them the same way
+    for input in &mut opts.input {
+        if let Some(md) = &mut input.metadata {
+            md.process_date = DateTime::parse_iso(&md.process_date, &zone)
+                .map_err(|e| eyre::eyre!("invalid process date for input {}: {e}", input.path))?
+                .to_string();
+        }
+    }
+    let mut pb = Playbook::new(opts.playbook_file_path);
+    for (name, settings) in &opts.module_opts {
+        match pb.pb.modules.iter_mut().find(|x| x.name() == name) {
+            Some(module) => module
+                .set_opts(settings)
+                .map_err(|e| eyre::eyre!("invalid module_opts of {name}: {e}"))?,
+            None => warn!("module_opts has {name}, which is not in the playbook"),
+        }
+    }
+    let mut tables = opts.lookup_tables.clone();
+    if let Some((table, file)) = &opts.json_lookup_table {
+        tables.insert(table.clone(), file.into());
+    }
+    for module in &mut pb.pb.modules {
+        if let Module::Lookup {
+            table, table_file, ..
+        } = module
+        {
+            *table_file = tables.get(table).cloned();
+        }
+    }
 
     let root: Arc<Path> = pb.channel_root_path.clone().into();
     let ingestion = pb.pb.modules[0].clone();
-    let tree = tree::PbTree::new(&pb.pb.modules);
-    // Call ingestion
-    let ingest = tokio::spawn(async move {
-        ingestion::ingest(&ingestion, opts.ingestion_opts, opts.input, root).await
-    });
+    let mut tree = tree::PbTree::new(&pb.pb.modules);
+    for name in &opts.excluded_modules {
+        if !pb.pb.modules.iter().any(|x| x.name() == name) {
+            warn!("excluded module {name} is not in the playbook");
+        }
+    }
+    tree.exclude(&opts.excluded_modules, opts.stop_at_excluded);
+    if let Some(name) = &opts.stop_after {
+        tree.stop_after(name);
+    }
+
+    // Messages flow into the pipeline while ingestion is still going
+    let (ingest_tx, ingest_rx) = tokio::sync::mpsc::channel(INGESTION_CAP);
+    let ingest = match opts.start_at {
+        // Seed the module with messages from disk instead of ingesting
+        Some(start) => {
+            let (node, parent) = tree
+                .detach(&start)
+                .wrap_err_with(|| format!("start module {start} is not in the tree"))?;
+            tree = node;
+            let (path, route) = match (opts.start_input, parent) {
+                (Some(path), _) => (path, None),
+                // With one file per stream there's no need to filter by route
+                (None, Some((parent, route))) => match opts.output_format {
+                    OutputFormat::Streams => (output.path(&parent, &route), None),
+                    _ => (output.path(&parent, &route), Some(route)),
+                },
+                (None, None) => eyre::bail!("{start} has no parent, pass start_input"),
+            };
+            let date = match &opts.process_date {
+                Some(date) => DateTime::parse_iso(date, &zone)
+                    .map_err(|e| eyre::eyre!("invalid process_date: {e}"))?
+                    .to_string(),
+                None => String::new(),
+            };
+            let date = date.into();
+            tokio::spawn(async move {
+                let mut bench = Benchmarker::default();
+                let res = ingestion::load_saved(&path, route.as_deref(), date, ingest_tx);
+                let res = res.await;
+                (res, bench.lap())
+            })
+        }
+        None => tokio::spawn(async move {
+            let mut bench = Benchmarker::default();
+            let res = ingestion::ingest(
+                &ingestion,
+                opts.ingestion_opts,
+                opts.input,
+                root,
+                zone,
+                ingest_tx,
+            );
+            let res = res.await;
+            (res, bench.lap())
+        }),
+    };
 
     // Setup JS isolate pools (in threads/channels?)
-    let scripts = pb.get_scripts().await;
+    let scripts = pb.get_scripts(&opts.excluded_modules).await;
     let tx = js::worker_pool(scripts);
-    let ingestion = ingest.await.unwrap();
 
     debug!("Executing playbook");
-    execute_playbook(tree, ingestion, tx).await;
+    let modules = execute_playbook(tree, ingest_rx, tx, output.clone()).await;
+    // The pipeline only ends once ingestion is done, one way or the other
+    let (ingestion, ingestion_time) = ingest.await.wrap_err("the ingestion task panicked")?;
+    let ingestion = ingestion.wrap_err("ingestion failed")?;
+    let mut modules = modules?;
+    let messages_in = ingestion.messages;
+    let rejects = ingestion.rejects.len() as u64;
+    if ingestion.filtered != 0 {
+        info!("{} messages were outside the eventTime", ingestion.filtered);
+    }
+    if rejects != 0 {
+        let path = opts.output_dir.join("_rejects.jsonl");
+        warn!("{rejects} lines couldn't be ingested, see {path:?}");
+        let mut lines = ingestion
+            .rejects
+            .iter()
+            .map(|x| serde_json::to_string(x).unwrap());
+        std::fs::write(&path, lines.join("\n")).wrap_err("writing the rejects")?;
+    }
+
+    modules.sort_by_key(|m| pb.pb.modules.iter().position(|x| x.name() == m.name));
+    if let Err(e) = output::write_index(&output, &pb.pb.modules, &modules) {
+        warn!("couldn't write the output index: {e}");
+    }
+
+    let summary = RunSummary {
+        messages_in,
+        rejects,
+        filtered: ingestion.filtered,
+        wall_time: bench.total(),
+        ingestion_time,
+        anonymizer_time: ingestion.anonymizer_time,
+        js_time: modules.iter().map(|m| m.js_time).sum(),
+        modules,
+    };
+    if opts.show_bench {
+        info!("run summary\n{summary:#}");
+    } else {
+        info!("run summary\n{summary}");
+    }
+    if let Err(e) = summary.write(&opts.output_dir) {
+        warn!("couldn't write the run summary: {e}");
+    }
 
-    // TODO: Collect results
     // TODO: Run assertions
+    Ok(())
 }
@@ -1,304 +1,683 @@
 use std::{
-    path::PathBuf,
-    ptr::{copy_nonoverlapping, write_bytes},
-    sync::{mpsc, Arc},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
 };
 
-use crossbeam::channel::Sender;
-use eyre::ContextCompat;
-use futures::{
-    future::{join, join_all, BoxFuture, JoinAll},
-    FutureExt,
+use crossbeam::{
+    channel::{bounded, never, select, unbounded, Receiver, Sender},
+    thread::{scope, Scope},
 };
+use eyre::{ContextCompat, WrapErr};
 use ijson::IString;
 use itertools::Itertools;
-use rayon::{
-    current_num_threads,
-    iter::{IntoParallelIterator, ParallelIterator},
-    spawn,
-};
-use sonic_rs::OwnedLazyValue;
-use tokio::task::JoinHandle;
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use crate::{
-    js::TaskData,
-    opts::{Message, MessageInner, Payload},
+    aggregation::Aggregator,
+    datapath::{self, DataPath, PathError, Root, Segment},
+    js::{RetData, TaskData},
+    lookup,
+    opts::{JsonObj, Message, MessageInner, OutputFormat, Payload, Source},
+    output::{OutputFile, OutputOpts},
     playbook::Module,
+    reporting::Reporter,
+    summary::ModuleMetrics,
     tree::PbTree,
 };
 
-pub async fn execute_playbook(pb_tree: PbTree, ingestion: Vec<Message>, tx: Sender<TaskData>) {
-    recurse(pb_tree, ingestion, tx, 0).await
+const CHANNEL_CAP: usize = 1024;
+const JS_WINDOW: usize = 4096;
+
+pub async fn execute_playbook(
+    pb_tree: PbTree,
+    mut ingestion: tokio::sync::mpsc::Receiver<Message>,
+    tx: Sender<TaskData>,
+    output: OutputOpts,
+) -> eyre::Result<Vec<ModuleMetrics>> {
+    tokio::task::spawn_blocking(move || {
+        let (metrics_tx, metrics_rx) = unbounded();
+        let (errors_tx, errors_rx) = unbounded();
+        let senders = Senders {
+            tx,
+            metrics: metrics_tx,
+            errors: errors_tx,
+        };
+        scope(|s| {
+            let (input_tx, input_rx) = bounded(CHANNEL_CAP);
+            spawn_module(s, pb_tree, input_rx, &senders, &output);
+            drop(senders);
+
+            while let Some(msg) = ingestion.blocking_recv() {
+                if input_tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        })
+        .map_err(|_| eyre::eyre!("a module panicked"))?;
+        if let Some(e) = errors_rx.into_iter().next() {
+            return Err(e);
+        }
+        Ok(metrics_rx.into_iter().collect())
+    })
+    .await
+    .wrap_err("the pipeline panicked")?
 }
 
-#[instrument(skip(data, tx, children))]
-fn recurse(
-    PbTree { module, children }: PbTree,
-    data: Vec<Message>,
+struct Senders {
     tx: Sender<TaskData>,
-    count: usize,
-) -> BoxFuture<'static, ()> {
-    let name = module.name().to_owned();
-    trace!(flow_name = name);
-    if count > 200 {
-        panic!("hit recursion limit");
+    metrics: Sender<ModuleMetrics>,
+    errors: Sender<eyre::Report>,
+}
+
+struct Outputs {
+    children: Vec<(String, Sender<Message>)>,
+    save: Sender<(String, Message)>,
+    metrics: ModuleMetrics,
+}
+
+impl Outputs {
+    fn emit(&mut self, route: &str, msg: Message) {
+        *self.metrics.streams.entry(route.to_owned()).or_default() += 1;
+        if route == "error" {
+            self.metrics.errors += 1;
+        }
+        // A child that hung up has panicked, the scope will report it.
+        for (_, tx) in self.children.iter().filter(|(r, _)| r == route) {
+            _ = tx.send(msg.clone());
+        }
+        _ = self.save.send((route.to_owned(), msg));
     }
+}
+
+fn spawn_module<'env>(
+    s: &Scope<'env>,
+    PbTree { module, children }: PbTree,
+    input: Receiver<Message>,
+    senders: &Senders,
+    output: &OutputOpts,
+) {
+    let children = children
+        .into_iter()
+        .map(|(child, route)| {
+            let (child_tx, child_rx) = bounded(CHANNEL_CAP);
+            spawn_module(s, child, child_rx, senders, output);
+            (route, child_tx)
+        })
+        .collect();
+    let mut out = Outputs {
+        children,
+        save: save(s, &module, output.clone(), senders.errors.clone()),
+        metrics: ModuleMetrics::new(module.name()),
+    };
+    let tx = senders.tx.clone();
+    let metrics = senders.metrics.clone();
+    let output = output.clone();
+
+    s.spawn(move |_| {
+        let t0 = Instant::now();
+        run_module(module, input, tx, &mut out, &output);
+        out.metrics.wall_time = t0.elapsed();
+        _ = metrics.send(out.metrics);
+    });
+}
+
+#[instrument(skip_all, fields(flow_name = module.name()))]
+fn run_module(
+    module: Module,
+    input: Receiver<Message>,
+    tx: Sender<TaskData>,
+    out: &mut Outputs,
+    output: &OutputOpts,
+) {
     use crate::playbook::Module::*;
-    async move {
-        match module {
-            Logic {
-                rules,
-                routes,
-                name,
-                input,
-            } => {
-                debug!("Logic flow {name}");
-                let (return_tx, rx) = mpsc::channel();
-                let len = data.len();
-
-                // First send the messages to be evaluated by the js engines
-                for (idx, data) in data.into_iter().enumerate() {
-                    let msg = TaskData {
-                        idx,
-                        data,
-                        module: name.clone(),
-                        tx: return_tx.clone(),
-                        exported_name: rules
-                            .last()
-                            .unwrap()
-                            .split_once(".js")
-                            .unwrap()
-                            .0
-                            .to_owned(),
-                    };
-                    tx.send(msg).unwrap();
+    match module {
+        Logic {
+            rules,
+            routes,
+            name,
+            input: _,
+        } => {
+            debug!("Logic flow {name}");
+            let exported_name = rules
+                .last()
+                .unwrap()
+                .split_once(".js")
+                .unwrap()
+                .0
+                .to_owned();
+            let (return_tx, return_rx) = unbounded();
+
+            // Results come back from the engines in any order, they are held here until every
+            // message before them has been emitted.
+            let mut pending: BTreeMap<usize, Message> = BTreeMap::new();
+            let (mut next_in, mut next_out) = (0, 0);
+            let mut input_open = true;
+            let closed = never();
+
+            while input_open || next_out < next_in {
+                let source = if input_open && next_in - next_out < JS_WINDOW {
+                    &input
+                } else {
+                    &closed
+                };
+                select! {
+                    recv(source) -> data => match data {
+                        Ok(data) => {
+                            out.metrics.messages_in += 1;
+                            let msg = TaskData {
+                                idx: next_in,
+                                data,
+                                module: name.clone(),
+                                tx: return_tx.clone(),
+                                exported_name: exported_name.clone(),
+                            };
+                            tx.send(msg).unwrap();
+                            next_in += 1;
+                        }
+                        Err(_) => input_open = false,
+                    },
+                    recv(return_rx) -> ret => {
+                        let RetData { idx, data, js_time, .. } = ret.unwrap();
+                        out.metrics.js_time += js_time;
+                        pending.insert(idx, data);
+                    }
+                }
+
+                while let Some(data) = pending.remove(&next_out) {
+                    for (route, data) in resolve_routes(&name, &routes, data) {
+                        out.emit(&route, data);
+                    }
+                    next_out += 1;
                 }
-                trace!("Sent all data");
-
-                // Collect all of the messages from the engines and their routes
-                let mut out = (0..len)
-                    .map(|i| {
-                        let crate::js::RetData { idx, data, .. } = rx.recv().unwrap();
-                        let route = data
-                            .inner
+            }
+            trace!("received all data");
+
+            for (route, count) in &out.metrics.streams {
+                info!(module = name, route, count, "routed messages");
+            }
+        }
+        Splitting {
+            array_path,
+            allow_empty,
+            record_split,
+            name,
+            input: _,
+        } => {
+            debug!("Splitting flow {name}");
+            let path = array_path.parse::<DataPath>();
+            if let Err(e) = &path {
+                error!("invalid arrayPath {array_path}: {e}");
+            }
+
+            for msg in input {
+                out.metrics.messages_in += 1;
+                for (route, msg) in
+                    split_message(msg, &path, &array_path, allow_empty, record_split)
+                {
+                    out.emit(route, msg);
+                }
+            }
+        }
+        Lookup { ref name, .. } => {
+            debug!("Lookup flow {name}");
+            let lookup = lookup::Lookup::new(&module);
+            if let Err(e) = &lookup {
+                error!("invalid Lookup module {name}: {e}");
+            }
+
+            for mut msg in input {
+                out.metrics.messages_in += 1;
+                let res = match &lookup {
+                    Ok(lookup) => lookup.apply(&mut msg),
+                    Err(e) => Err(format!("invalid Lookup module: {e}")),
+                };
+                match res {
+                    Ok(()) => out.emit("output", msg),
+                    Err(reason) => {
+                        debug!(module = name, "{reason}");
+                        msg.inner
                             .billingmediation
-                            .get("route")
-                            .map(|v| v.to_string().clone())
-                            .unwrap_or_else(|| String::from("output")); // If no route that means output.
-                        (idx, route.clone(), data)
-                    })
-                    .collect_vec();
-                out.sort_unstable_by_key(|x| x.0);
-                trace!("received all data");
-                let save = save(name.to_owned(), out.iter().map(|x| x.2.clone()).collect());
-
-                // Route accordingly
-                let iter = children.into_iter().map(|(module, route)| {
-                    let data = out
-                        .clone()
-                        .into_iter()
-                        .filter_map(|(_, m_route, data)| (route == *m_route).then_some(data))
-                        .collect_vec();
-                    recurse(module, data, tx.clone(), count + 1)
-                });
-                let exec = join_all(iter);
-                _ = futures::future::join(exec, save).await;
+                            .insert("error".to_owned(), reason.into());
+                        out.emit("error", msg);
+                    }
+                }
+            }
+        }
+        Reporting {
+            scheduling,
+            format: _,
+            fields,
+            subscriber,
+            name,
+            input: _,
+        } => {
+            debug!("Reporting flow {name}");
+            let mut reporter = Reporter::new(
+                &name,
+                &scheduling,
+                &fields,
+                subscriber.as_deref(),
+                output.clock.clone(),
+                &output.reports_dir,
+            );
+            if let Err(e) = &reporter {
+                error!("invalid Reporting module {name}: {e}");
             }
-            Splitting {
-                array_path,
-                allow_empty,
-                name,
-                input,
-            } => {
-                debug!("Splitting flow {name}");
-
-                let mut path = array_path.split('.').collect_vec();
-                let is_bm = path[0] == "billingmediation";
-
-                let data = data
-                    .iter()
-                    .flat_map(|Message { inner, date }| {
-                        if is_bm {
-                            split_billingmediation(
-                                inner.billingmediation.clone(),
-                                &path[1..],
-                                inner.payload.clone(),
-                                date.clone(),
-                            )
-                        } else {
-                            split_payload(
-                                inner.payload.clone(),
-                                &path,
-                                inner.billingmediation.clone(),
-                                date.clone(),
-                            )
-                        }
-                    })
-                    .collect_vec();
 
-                route_output(&name, children, data, tx, count).await;
+            for mut msg in input {
+                out.metrics.messages_in += 1;
+                let res = match &mut reporter {
+                    Ok(reporter) => reporter.push(&msg),
+                    Err(e) => Err(format!("invalid Reporting module: {e}")),
+                };
+                if let Err(reason) = res {
+                    debug!(module = name, "{reason}");
+                    msg.inner
+                        .billingmediation
+                        .insert("error".to_owned(), reason.into());
+                    out.emit("error", msg);
+                }
             }
-            Lookup { name, input } => todo!(),
-            Reporting {
-                scheduling,
-                format,
-                name,
-                input,
-            } => todo!(),
-            Aggregation { key, name, input } => todo!(),
-            Deduplication { key, name, input } => todo!(),
-            _ => {
-                debug!("Ingestion flow {name}");
-                route_output(&name, children, data, tx, count).await;
+            match reporter.map(Reporter::finish) {
+                Ok(Ok(n)) => info!(module = name, "wrote {n} reports"),
+                Ok(Err(e)) => error!("couldn't write the reports of {name}: {e}"),
+                Err(_) => {}
+            }
+        }
+        Aggregation { ref name, .. } => {
+            debug!("Aggregation flow {name}");
+            let mut aggregator = Aggregator::new(&module, output.clock.zone.clone());
+            if let Err(e) = &aggregator {
+                error!("invalid Aggregation module {name}: {e}");
+            }
+
+            for mut msg in input {
+                out.metrics.messages_in += 1;
+                match &mut aggregator {
+                    Ok(aggregator) => aggregator.push(msg, &mut |route, msg| out.emit(route, msg)),
+                    Err(e) => {
+                        msg.inner.billingmediation.insert(
+                            "error".to_owned(),
+                            format!("invalid Aggregation module: {e}").into(),
+                        );
+                        out.emit("error", msg);
+                    }
+                }
+            }
+            if let Ok(aggregator) = aggregator {
+                aggregator.finish(&mut |route, msg| out.emit(route, msg));
+            }
+        }
+        Deduplication {
+            key,
+            name,
+            input: _,
+        } => {
+            debug!("Deduplication flow {name}");
+            let key = datapath::parse_all(&key).map_err(|e| format!("{e} in key"));
+            if let Err(e) = &key {
+                error!("invalid Deduplication module {name}: {e}");
+            }
+            let mut seen = HashSet::new();
+            let mut duplicates = 0;
+            for mut msg in input {
+                out.metrics.messages_in += 1;
+                let key = match &key {
+                    Ok(key) => key,
+                    Err(e) => {
+                        let reason = format!("invalid Deduplication module: {e}");
+                        msg.inner
+                            .billingmediation
+                            .insert("error".to_owned(), reason.into());
+                        out.emit("error", msg);
+                        continue;
+                    }
+                };
+                // Values aren't Hash, their json is
+                let key = match datapath::key_of(&msg, key) {
+                    Ok(key) => serde_json::Value::from(key).to_string(),
+                    Err(e) => {
+                        msg.inner
+                            .billingmediation
+                            .insert("error".to_owned(), format!("no key: {e}").into());
+                        out.emit("error", msg);
+                        continue;
+                    }
+                };
+                if seen.insert(key) {
+                    out.emit("output", msg);
+                } else {
+                    duplicates += 1;
+                }
+            }
+            info!(module = name, duplicates, "dropped duplicates");
+        }
+        _ => {
+            debug!("Ingestion flow {}", module.name());
+            for msg in input {
+                out.metrics.messages_in += 1;
+                out.emit("output", msg);
             }
         }
     }
-    .boxed()
 }
 
-fn save(name: String, data: Vec<Message>) -> JoinHandle<()> {
-    tokio::task::spawn_blocking(move || {
-        let data = data.iter().map(|x| x.inner.to_string()).join("\n");
-        trace!("beginning save for {name}");
-        std::fs::write(PathBuf::from("bmp_emulator").join(&name), data).unwrap();
-        trace!("finished writing for {name}");
-    })
+/// Reads `billingmediation.route`, which can be a string or an array of strings (fan-out), and
+/// pairs the message with every stream it goes to. No route means `output`. Routes that the
+/// module doesn't declare go to `error` with a diagnostic in `billingmediation.error`.
+fn resolve_routes(module: &str, declared: &[String], data: Message) -> Vec<(String, Message)> {
+    use serde_json::Value;
+
+    let routes = match data.inner.billingmediation.get("route") {
+        None | Some(Value::Null) => return vec![("output".to_owned(), data)],
+        Some(Value::String(route)) => Ok(vec![route.clone()]),
+        Some(Value::Array(arr)) if !arr.is_empty() => arr
+            .iter()
+            .map(|v| v.as_str().map(str::to_owned).ok_or(v))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|v| format!("route must be a string, got {v}")),
+        Some(v) => Err(format!(
+            "route must be a string or a non empty array of strings, got {v}"
+        )),
+    };
+
+    let is_declared =
+        |route: &str| route == "output" || route == "error" || declared.iter().any(|x| x == route);
+
+    let to_error = |mut data: Message, diagnostic: String| {
+        warn!(module, "{diagnostic}");
+        data.inner
+            .billingmediation
+            .insert("error".to_owned(), diagnostic.into());
+        ("error".to_owned(), data)
+    };
+
+    let routes = match routes {
+        Ok(routes) => routes,
+        Err(diagnostic) => return vec![to_error(data, diagnostic)],
+    };
+    let (declared, undeclared): (Vec<_>, Vec<_>) =
+        routes.into_iter().unique().partition(|x| is_declared(x));
+    // At most one copy goes to `error`, with every undeclared route in its diagnostic
+    let mut ret = declared
+        .into_iter()
+        .filter(|x| undeclared.is_empty() || x != "error")
+        .map(|route| (route, data.clone()))
+        .collect_vec();
+    if !undeclared.is_empty() {
+        let names = undeclared.iter().map(|x| format!("'{x}'")).join(", ");
+        let diagnostic = match undeclared.len() {
+            1 => format!("route {names} is not declared in the routes of {module}"),
+            _ => format!("routes {names} are not declared in the routes of {module}"),
+        };
+        ret.push(to_error(data, diagnostic));
+    }
+    ret
 }
 
-async fn route_output(
-    name: &str,
-    children: Vec<(PbTree, String)>,
-    data: Vec<Message>,
-    tx: Sender<TaskData>,
-    count: usize,
-) {
-    let save = save(name.to_owned(), data.clone());
-    let iter = children.into_iter().map(|(module, _)| {
-        let data = data.clone();
-        let tx = tx.clone();
-        tokio::spawn(async move { recurse(module.clone(), data, tx, count + 1).await })
+fn save<'env>(
+    s: &Scope<'env>,
+    module: &Module,
+    output: OutputOpts,
+    errors: Sender<eyre::Report>,
+) -> Sender<(String, Message)> {
+    let (tx, rx) = bounded::<(String, Message)>(CHANNEL_CAP);
+    let name = module.name().to_owned();
+    let streams = match output.format {
+        OutputFormat::Streams => module.streams().map(str::to_owned).collect_vec(),
+        _ => vec![String::new()],
+    };
+    s.spawn(move |_| {
+        trace!("beginning save for {name}");
+        let create = |route: &str| -> eyre::Result<_> {
+            let path = output.path(&name, route);
+            let file = OutputFile::create(&path, &output);
+            Ok((
+                file.wrap_err_with(|| format!("can't create {path:?}"))?,
+                path,
+            ))
+        };
+        let save = || -> eyre::Result<()> {
+            let mut files: HashMap<String, (OutputFile, PathBuf)> = HashMap::new();
+            for (route, msg) in &rx {
+                // Everything goes to the same file unless there's one per stream
+                let key = match output.format {
+                    OutputFormat::Streams => route.clone(),
+                    _ => String::new(),
+                };
+                let (file, path) = match files.entry(key) {
+                    Entry::Occupied(x) => x.into_mut(),
+                    Entry::Vacant(x) => {
+                        let file = create(x.key())?;
+                        x.insert(file)
+                    }
+                };
+                file.write(&msg, &route)
+                    .wrap_err_with(|| format!("can't write to {path:?}"))?;
+            }
+            for stream in streams {
+                if let Entry::Vacant(x) = files.entry(stream) {
+                    let file = create(x.key())?;
+                    x.insert(file);
+                }
+            }
+            for (file, path) in files.drain().map(|x| x.1) {
+                file.finish()
+                    .wrap_err_with(|| format!("can't write to {path:?}"))?;
+            }
+            Ok(())
+        };
+        if let Err(e) = save() {
+            _ = errors.send(e.wrap_err(format!("saving the outputs of {name}")));
+        }
+        trace!("finished writing for {name}");
     });
-
-    let recursive = join_all(iter);
-    trace!("finished recursing");
-    let (a, b) = futures::future::join(recursive, save).await;
-    for i in a {
-        i.unwrap();
-    }
-    b.unwrap();
+    tx
 }
 
-fn split_billingmediation(
-    bm: serde_json::Map<String, serde_json::Value>,
-    path: &[&str],
-    payload: Payload,
-    date: IString,
-) -> Vec<Message> {
-    let mut base = bm.clone();
-    let first = path[0];
-    let mut current = base.get_mut(first).unwrap();
-
-    for c in path {
-        current = current.get_mut(c).expect("bad array_path");
+/// What a Splitting module emits for `msg`: one message per element of the array at `path`, or
+/// `msg` itself once when there's nothing to split and `allow_empty` is set, without the empty
+/// array. The others go to `error`.
+fn split_message(
+    mut msg: Message,
+    path: &Result<DataPath, String>,
+    array_path: &str,
+    allow_empty: bool,
+    record_split: bool,
+) -> Vec<(&'static str, Message)> {
+    fn error(mut msg: Message, reason: String) -> Vec<(&'static str, Message)> {
+        debug!("{reason}");
+        msg.inner
+            .billingmediation
+            .insert("error".to_owned(), reason.into());
+        vec![("error", msg)]
     }
-    let arrays = current
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|x| vec![x.to_owned()])
-        .collect_vec();
 
-    let out = arrays.into_iter().map(move |arr| {
-        let mut base = bm.clone();
-        let mut current = base.get_mut(first).unwrap();
-        for c in &path[1..] {
-            current = current.get_mut(c).expect("bad array_path");
+    let split = match path {
+        Ok(path) => split(&msg, path),
+        Err(e) => Err(SplitError::Invalid(format!("invalid arrayPath: {e}"))),
+    };
+    let parent_id = record_split.then(|| parent_id(&msg));
+    let record = |msg: &mut Message, index: Option<usize>, count: usize| {
+        if let Some(id) = &parent_id {
+            let split = serde_json::json!({
+                "index": index,
+                "count": count,
+                "parentId": id,
+            });
+            msg.inner.billingmediation.insert("split".to_owned(), split);
         }
-        *current = arr.into();
-
-        Message {
-            inner: MessageInner {
-                billingmediation: base,
-                payload: payload.clone(), // This is an Arc, feels good ;)
-            },
-            date: date.clone(),
+    };
+    match split {
+        Ok(split) => {
+            let count = split.len();
+            let mut ret = Vec::with_capacity(count);
+            for (index, mut msg) in split.into_iter().enumerate() {
+                record(&mut msg, Some(index), count);
+                ret.push(("output", msg));
+            }
+            ret
         }
-    });
-    out.collect()
+        Err(e @ (SplitError::Missing(_) | SplitError::Empty)) if allow_empty => {
+            let deleted = match (&e, path) {
+                (SplitError::Empty, Ok(path)) => path.delete(&mut msg).map(drop),
+                _ => Ok(()),
+            };
+            match deleted {
+                Ok(()) => {
+                    record(&mut msg, None, 0);
+                    vec![("output", msg)]
+                }
+                Err(e) => error(msg, format!("can't remove {array_path}: {e}")),
+            }
+        }
+        Err(e) => error(msg, e.reason(array_path)),
+    }
 }
 
-fn split_payload(
-    json: Payload,
-    path: &[&str],
-    bm: serde_json::Map<String, serde_json::Value>,
-    date: IString,
-) -> Vec<Message> {
-    let ret = json_split(json, path);
-
-    ret.iter()
-        .map(|payload| Message {
-            inner: MessageInner {
-                billingmediation: bm.clone(),
-                payload: payload.clone(),
-            },
-            date: date.clone(),
-        })
-        .collect()
+/// Identifies the message a split one comes from: the line it was ingested from, and where it was
+/// split from if it was recorded already, so identical lines still get their own ids. Messages
+/// without a source are identified by their content.
+fn parent_id(msg: &Message) -> String {
+    let hash = match &msg.source {
+        Some(Source { file, line, index }) => {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(file.as_bytes());
+            hasher.update(&line.to_le_bytes());
+            hasher.update(&index.to_le_bytes());
+            if let Some(split) = msg.inner.billingmediation.get("split") {
+                hasher.update(split.to_string().as_bytes());
+            }
+            hasher.finalize()
+        }
+        None => blake3::hash(msg.to_string().as_bytes()),
+    };
+    hash.to_hex()[..16].to_owned()
 }
 
-fn json_split(json: Payload, path: &[&str]) -> Vec<Payload> {
-    let arr = sonic_rs::get_from_str(&json, path).unwrap_or_else(|e| panic!("{} {}", e, json));
-    let arr = arr.as_raw_str();
+#[derive(Debug)]
+enum SplitError {
+    Missing(PathError),
+    Empty,
+    NotArray(&'static str),
+    Invalid(String),
+}
 
-    let owned_arr: sonic_rs::Array = sonic_rs::from_str(arr).unwrap();
-    if owned_arr.is_empty() {
-        return vec![];
-    }
-    if owned_arr.len() == 1 {
-        return vec![json.clone()];
+impl SplitError {
+    fn reason(&self, path: &str) -> String {
+        match self {
+            SplitError::Missing(e) => format!("nothing at {path}: {e}"),
+            SplitError::NotArray(kind) => format!("{path} is {}, not an array", datapath::a(kind)),
+            SplitError::Empty => format!("{path} is empty and allowEmpty is false"),
+            SplitError::Invalid(reason) => reason.clone(),
+        }
     }
+}
 
-    let mut local_copy = String::from(&*json);
-    let mut ret: Vec<Payload> = Vec::with_capacity(owned_arr.len());
-
-    for (n, i) in owned_arr.iter().enumerate() {
-        unsafe {
-            // Construct a slice that goes from [1, 2, 3, 4]
-            //                                   ^        ^
-
-            let offset = arr.as_ptr().sub_ptr(json.as_ptr());
-            let ptr = local_copy.as_mut_ptr().add(1 + offset);
-            let len = arr.len() - 2;
-            let internal_thing = std::slice::from_raw_parts_mut(ptr, len);
-
-            let i = i.to_string();
-            let source = i.as_bytes();
-
-            let (head, rest) = internal_thing.split_at_mut(source.len());
-            head.copy_from_slice(source);
-            rest.fill(b' ');
+/// Splits `msg` in one message per element of the array at `path`. In every one of them the array
+/// is replaced by an array holding just that element, like BMP does.
+fn split(msg: &Message, path: &DataPath) -> Result<Vec<Message>, SplitError> {
+    let with_inner = |inner| Message {
+        inner,
+        date: msg.date.clone(),
+        source: msg.source.clone(),
+    };
+    let ret = match path.root() {
+        (Root::Payload, path) => split_payload(&msg.inner.payload, path)?
+            .into_iter()
+            .map(|payload| MessageInner {
+                billingmediation: msg.inner.billingmediation.clone(),
+                payload,
+            })
+            .map(with_inner)
+            .collect(),
+        (Root::Billingmediation, path) => {
+            split_billingmediation(&msg.inner.billingmediation, path)?
+                .into_iter()
+                .map(|billingmediation| MessageInner {
+                    billingmediation,
+                    payload: msg.inner.payload.clone(), // This is an Arc, feels good ;)
+                })
+                .map(with_inner)
+                .collect()
         }
+    };
+    Ok(ret)
+}
 
-        // Move last string
-        if n == owned_arr.len() - 1 {
-            ret.push(local_copy.into());
-            break; // This appeases the borrowck ;)
-        } else {
-            ret.push(Arc::from(local_copy.clone()));
+fn split_billingmediation(bm: &JsonObj, path: &[Segment]) -> Result<Vec<JsonObj>, SplitError> {
+    use serde_json::Value;
+
+    let bm = Value::Object(bm.clone());
+    let arr = match datapath::lookup(&bm, Root::Billingmediation, path) {
+        Err(e) => return Err(SplitError::Missing(e)),
+        Ok(Value::Array(arr)) if arr.is_empty() => return Err(SplitError::Empty),
+        Ok(Value::Array(arr)) => arr.clone(),
+        Ok(x) => return Err(SplitError::NotArray(datapath::kind(x))),
+    };
+    let ret = arr.into_iter().map(|x| {
+        let mut bm = bm.clone();
+        datapath::set(&mut bm, Root::Billingmediation, path, Value::Array(vec![x])).unwrap();
+        match bm {
+            Value::Object(bm) => bm,
+            _ => unreachable!(),
         }
+    });
+    Ok(ret.collect())
+}
+
+fn split_payload(json: &Payload, path: &[Segment]) -> Result<Vec<Payload>, SplitError> {
+    let Some(arr) = datapath::span(json, path).map_err(SplitError::Invalid)? else {
+        // Only parsed to tell which segment is missing
+        let payload: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| SplitError::Invalid(format!("invalid payload: {e}")))?;
+        return Err(match datapath::lookup(&payload, Root::Payload, path) {
+            Err(e) => SplitError::Missing(e),
+            Ok(_) => SplitError::Invalid("invalid payload".to_owned()),
+        });
+    };
+    let Some(elements) = datapath::elements(json, arr.clone()).map_err(SplitError::Invalid)? else {
+        let kind = serde_json::from_str(&json[arr])
+            .map_or("invalid value", |x: serde_json::Value| datapath::kind(&x));
+        return Err(SplitError::NotArray(kind));
+    };
+    match elements.len() {
+        0 => return Err(SplitError::Empty),
+        // Already split
+        1 => return Ok(vec![json.clone()]),
+        _ => {}
     }
-    ret
+
+    let (head, tail) = (&json[..arr.start], &json[arr.end..]);
+    let ret = elements.into_iter().map(|element| {
+        let element = &json[element];
+        let mut part = String::with_capacity(head.len() + element.len() + tail.len() + 2);
+        part.push_str(head);
+        part.push('[');
+        part.push_str(element);
+        part.push(']');
+        part.push_str(tail);
+        Payload::from(part)
+    });
+    Ok(ret.collect())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+
+    fn path(s: &str) -> Vec<Segment> {
+        s.parse::<DataPath>().unwrap().segments
+    }
+
     #[test]
     fn split_json_nums() {
         let json = r#" {"foo": { "bar": [0,1,2], "unused": null } }"#;
 
-        for (n, i) in json_split(json.to_owned().into(), &["foo", "bar"])
+        for (n, i) in split_payload(&json.to_owned().into(), &path("foo.bar"))
+            .unwrap()
             .iter()
             .enumerate()
         {
@@ -323,7 +702,8 @@ mod test {
     fn split_json_strs() {
         let json = r#" {"foo": { "bar": ["0","1","2"] } }"#;
 
-        for (n, i) in json_split(json.to_owned().into(), &["foo", "bar"])
+        for (n, i) in split_payload(&json.to_owned().into(), &path("foo.bar"))
+            .unwrap()
             .iter()
             .enumerate()
         {
@@ -344,7 +724,8 @@ mod test {
     fn split_json_objs() {
         let json = r#" {"foo": { "bar": [ {"baz": 0}, {"baz": 1}, {"baz": 2} ] } }"#;
 
-        for (n, i) in json_split(json.to_owned().into(), &["foo", "bar"])
+        for (n, i) in split_payload(&json.to_owned().into(), &path("foo.bar"))
+            .unwrap()
             .iter()
             .enumerate()
         {
@@ -365,4 +746,154 @@ mod test {
             assert_eq!(val as usize, n);
         }
     }
+
+    #[test]
+    fn split_nested_paths() {
+        // Elements longer once re-serialized, behind an index
+        let json = r#"{"a": [{}, {"b": [1e2, "\u00e9", [[0]]]}], "c": 1}"#;
+        let parts = split_payload(&json.to_owned().into(), &path("a[1].b")).unwrap();
+        let parts = parts.iter().map(|x| x.as_str()).collect_vec();
+        assert_eq!(
+            parts,
+            [
+                r#"{"a": [{}, {"b": [1e2]}], "c": 1}"#,
+                r#"{"a": [{}, {"b": ["\u00e9"]}], "c": 1}"#,
+                r#"{"a": [{}, {"b": [[[0]]]}], "c": 1}"#,
+            ]
+        );
+        let one = Payload::from(r#"{"a": [1]}"#.to_owned());
+        assert!(Payload::ptr_eq(
+            &split_payload(&one, &path("a")).unwrap()[0],
+            &one
+        ));
+        assert!(matches!(
+            split_payload(&json.to_owned().into(), &path("c")),
+            Err(SplitError::NotArray("number"))
+        ));
+        let missing = split_payload(&json.to_owned().into(), &path("a[2].b"));
+        assert_eq!(
+            missing.unwrap_err().reason("a[2].b"),
+            "nothing at a[2].b: index 2 is out of bounds, a has 2 elements"
+        );
+
+        let bm = serde_json::json!({"x": {"items": [1, 2]}});
+        let bm = bm.as_object().unwrap();
+        let parts = split_billingmediation(bm, &path("x.items")).unwrap();
+        assert_eq!(parts[1]["x"]["items"], serde_json::json!([2]));
+
+        assert!("a..b".parse::<DataPath>().is_err());
+        assert!("a[x]".parse::<DataPath>().is_err());
+        assert_eq!(path("a[0][1].b").len(), 4);
+    }
+
+    #[test]
+    fn allow_empty() {
+        let path = "items".parse::<DataPath>().map_err(|e| e.to_string());
+        let split = |payload: &str, allow_empty| {
+            let msg = Message {
+                inner: MessageInner::from(payload.to_owned()),
+                date: IString::from(""),
+                source: None,
+            };
+            split_message(msg, &path, "items", allow_empty, true)
+                .into_iter()
+                .map(|(route, msg)| {
+                    let payload = serde_json::from_str::<serde_json::Value>(&msg.inner.payload);
+                    (route, payload.unwrap(), msg.inner.billingmediation)
+                })
+                .collect_vec()
+        };
+
+        for input in [r#"{"a": 1}"#, r#"{"a": 1, "items": []}"#] {
+            let out = split(input, true);
+            assert_eq!(out.len(), 1);
+            let (route, payload, bm) = &out[0];
+            assert_eq!(*route, "output");
+            assert_eq!(*payload, serde_json::json!({"a": 1}));
+            assert_eq!(bm["split"]["index"], serde_json::Value::Null);
+            assert_eq!(bm["split"]["count"], 0);
+
+            let out = split(input, false);
+            assert_eq!(out[0].0, "error");
+        }
+        let out = split(r#"{"items": [1, 2]}"#, true);
+        assert_eq!(out[1].1, serde_json::json!({"items": [2]}));
+    }
+
+    #[test]
+    fn parent_ids_follow_the_source() {
+        let msg = |line: Option<usize>| Message {
+            inner: MessageInner::from(String::from(r#"{"items": [1, 2]}"#)),
+            date: IString::from(""),
+            source: line.map(|line| Source {
+                file: "input".into(),
+                line,
+                index: 0,
+            }),
+        };
+        assert_eq!(parent_id(&msg(Some(1))), parent_id(&msg(Some(1))));
+        assert_ne!(parent_id(&msg(Some(1))), parent_id(&msg(Some(2))));
+        assert_eq!(parent_id(&msg(None)), parent_id(&msg(None)));
+
+        // Splitting a split message again
+        let path = "items".parse::<DataPath>();
+        let split = split_message(msg(Some(1)), &path, "items", false, true);
+        let ids = split
+            .into_iter()
+            .map(|(_, msg)| parent_id(&msg))
+            .collect_vec();
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[tokio::test]
+    async fn save_errors_are_returned() {
+        let module = serde_json::json!({"type": "Deduplication", "name": "dedup", "key": ["a"]});
+        let tree = PbTree {
+            module: serde_json::from_value(module).unwrap(),
+            children: vec![],
+        };
+        let output = OutputOpts {
+            dir: PathBuf::from("/nonexistent/output"),
+            format: OutputFormat::Jsonl,
+            compression: Default::default(),
+            reports_dir: PathBuf::from("/nonexistent/reports"),
+            clock: Default::default(),
+        };
+        let (ingest_tx, ingest_rx) = tokio::sync::mpsc::channel(1);
+        drop(ingest_tx);
+        let (tx, _rx) = unbounded();
+        let e = execute_playbook(tree, ingest_rx, tx, output)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "saving the outputs of dedup");
+        assert!(format!("{e:#}").contains(r#"can't create "/nonexistent/output/dedup""#));
+    }
+
+    #[test]
+    fn routes_fan_out_and_reject_undeclared() {
+        let mut msg = Message {
+            inner: MessageInner::from(String::from("{}")),
+            date: IString::from(""),
+            source: None,
+        };
+        msg.inner
+            .billingmediation
+            .insert("route".to_owned(), serde_json::json!(["flow", "nope"]));
+        let routes = resolve_routes("routing", &["flow".to_owned()], msg.clone());
+        let names = routes.iter().map(|x| x.0.as_str()).collect_vec();
+        assert_eq!(names, ["flow", "error"]);
+        assert!(routes[1].1.inner.billingmediation.contains_key("error"));
+
+        msg.inner.billingmediation.insert(
+            "route".to_owned(),
+            serde_json::json!(["flow", "nope", "flow", "error", "other", "nope"]),
+        );
+        let routes = resolve_routes("routing", &["flow".to_owned()], msg);
+        let names = routes.iter().map(|x| x.0.as_str()).collect_vec();
+        assert_eq!(names, ["flow", "error"]);
+        assert_eq!(
+            routes[1].1.inner.billingmediation["error"],
+            "routes 'nope', 'other' are not declared in the routes of routing"
+        );
+    }
 }
@@ -1,14 +1,16 @@
 use std::{
-    hash::{DefaultHasher, Hash, Hasher},
+    collections::{HashMap, VecDeque},
     path::{Path, PathBuf},
-    process::Stdio,
     str::FromStr,
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use eyre::Context;
-use futures::stream::FuturesUnordered;
+use eyre::{Context, ContextCompat};
+use futures::{
+    stream::{FuturesOrdered, FuturesUnordered},
+    StreamExt,
+};
 use rayon::{
     iter::{IntoParallelIterator, ParallelBridge, ParallelIterator},
     slice::ParallelSlice,
@@ -17,175 +19,947 @@ use rayon::{
 use ijson::{IObject, IString, IValue as Value};
 use itertools::Itertools;
 use regex::Regex;
+use serde::Serialize;
 use tokio::{
-    fs::{self, read_to_string},
-    io::AsyncWriteExt,
-    process::Command,
+    fs,
+    sync::mpsc::{channel, Receiver, Sender},
 };
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use crate::{
+    anonymizer::{self, AnonLine},
+    cache::{self, Cache},
+    datapath::{self, DataPath, PathError, Root},
+    datetime::{Civil, DatePattern, DateTime, TimeZone},
     driver::Benchmarker,
-    opts::{IngestionOpts, Input, Message, MessageInner, Payload},
-    playbook::Module,
+    opts::{IngestionOpts, Input, InputMetadata, Message, MessageInner, Payload, Reserved, Source},
+    playbook::{EventTimeField, EventTimeFilter, FieldSelection, Module},
 };
 
-#[instrument]
+#[derive(Debug, Clone)]
+pub struct Ingested {
+    pub messages: u64,
+    pub rejects: Vec<Reject>,
+    pub anonymizer_time: Duration,
+    pub filtered: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Reject {
+    pub file: String,
+    pub line: usize,
+    pub index: usize,
+    pub grammar: PathBuf,
+    pub reason: String,
+    pub input: String,
+}
+
+pub struct EventTime {
+    field: Option<(DataPath, DatePattern)>,
+    start: Option<DateTime>,
+    end: Option<DateTime>,
+    zone: TimeZone,
+}
+
+impl EventTime {
+    pub fn new(filter: &EventTimeFilter, zone: TimeZone) -> Result<Self, String> {
+        let date = |x: &Option<String>| x.as_deref().map(|x| DateTime::parse_iso(x, &zone));
+        Ok(Self {
+            start: date(&filter.start).transpose()?,
+            end: date(&filter.end).transpose()?,
+            ..Self::field(&filter.field, zone)?
+        })
+    }
+
+    pub fn field(field: &EventTimeField, zone: TimeZone) -> Result<Self, String> {
+        let field = match (field.path.as_str(), &field.format) {
+            ("", None) => None,
+            ("", Some(_)) => return Err("format is only allowed with a path".to_owned()),
+            (_, None) => return Err("format is required with a path".to_owned()),
+            (path, Some(format)) => {
+                let path = path
+                    .parse()
+                    .map_err(|e| format!("invalid path {path}: {e}"))?;
+                Some((path, format.parse()?))
+            }
+        };
+        Ok(Self {
+            field,
+            start: None,
+            end: None,
+            zone,
+        })
+    }
+
+    pub fn contains(&self, msg: &Message) -> Result<bool, String> {
+        let time = self.time(msg)?;
+        Ok(self.start.is_none_or(|x| x <= time) && self.end.is_none_or(|x| time < x))
+    }
+
+    pub fn time(&self, msg: &Message) -> Result<DateTime, String> {
+        Ok(match &self.field {
+            None => msg
+                .process_date()
+                .ok_or("the message has no process date")?,
+            Some((path, format)) => match path.get(msg).map_err(|e| e.to_string())? {
+                serde_json::Value::String(x) => format.parse(&x, &self.zone)?,
+                x => {
+                    return Err(format!(
+                        "{path} is {}, not a string",
+                        datapath::a(datapath::kind(&x))
+                    ))
+                }
+            },
+        })
+    }
+}
+
+pub struct Selection {
+    add: Vec<DataPath>,
+    del: Vec<DataPath>,
+}
+
+impl Selection {
+    pub fn new(select: &[FieldSelection]) -> Result<Self, String> {
+        let (mut add, mut del) = (vec![], vec![]);
+        for item in select {
+            let (paths, path) = match item {
+                FieldSelection::Add(path) => (&mut add, path),
+                FieldSelection::Del(path) => (&mut del, path),
+            };
+            let parsed: DataPath = path
+                .parse()
+                .map_err(|e| format!("invalid path {path}: {e}"))?;
+            if parsed.root().1.is_empty() {
+                return Err(format!("{path} is a whole part of the message"));
+            }
+            paths.push(parsed);
+        }
+        if let Some(path) = add.iter().find(|x| x.root().0 == Root::Billingmediation) {
+            return Err(format!(
+                "{path} isn't in the payload, only payload paths can be added"
+            ));
+        }
+        Ok(Self { add, del })
+    }
+
+    /// Keeps only the values at the `add` paths of the payload when there are any, then removes
+    /// the values at the `del` paths. Paths that lead nowhere are skipped.
+    pub fn apply(&self, msg: &mut Message) -> Result<(), PathError> {
+        let absent = |e: &PathError| {
+            matches!(
+                e,
+                PathError::MissingKey { .. }
+                    | PathError::OutOfBounds { .. }
+                    | PathError::WrongType { .. }
+            )
+        };
+        if !self.add.is_empty() {
+            let payload: serde_json::Value = serde_json::from_str(&msg.inner.payload)
+                .map_err(|e| PathError::Invalid(format!("invalid payload: {e}")))?;
+            let mut kept = serde_json::Value::Object(Default::default());
+            for path in &self.add {
+                match datapath::lookup(&payload, Root::Payload, &path.segments) {
+                    Ok(value) => {
+                        datapath::set(&mut kept, Root::Payload, &path.segments, value.clone())?
+                    }
+                    Err(e) if absent(&e) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            msg.inner.payload = kept.to_string().into();
+        }
+        for path in &self.del {
+            match path.delete(msg) {
+                Err(e) if !absent(&e) => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+const BATCHES_IN_FLIGHT: usize = 4;
+const LINES_CAP: usize = 1024;
+
+#[instrument(skip(out))]
 pub async fn ingest(
     md: &Module,
     opts: IngestionOpts,
     input: Vec<Input>,
     root: Arc<Path>,
-) -> Vec<Message> {
-    let res = input.into_iter().map(
-        |Input {
-             path: input,
-             metadata,
-         }| {
-            debug!("starting ingestion from {input}");
-            let root = root.clone();
-            let metadata = metadata.clone();
-            let cachedir = PathBuf::from(".cache");
-            std::fs::create_dir_all(&cachedir);
-            let regex = opts.regex.as_ref().map(|x| Regex::from_str(x).unwrap());
-            trace!("perf: ingestion start");
-            async move {
-                // PERF: it's already valid utf8
-                let res = read_to_string(&input).await.context(input).unwrap();
-                trace!("perf: loaded input to string");
-                let mut lines = res
-                    .trim()
-                    .lines()
-                    .skip(opts.head as usize)
-                    .filter(|line| regex.as_ref().map(|x| x.is_match(line)).unwrap_or(true))
-                    .collect_vec();
-                lines.truncate(lines.len() - opts.tail as usize);
-                let s = lines.join("\n");
-                trace!("perf: Applied ingestion options");
-                debug!("got {} messages", lines.len());
-
-                let mut h = DefaultHasher::default();
-                s.hash(&mut h);
-                let hash = h.finish().to_string();
-                // PERF: this is probably not necessary but why not use file names or something
-                trace!("perf: hash messages");
-                let inner: Vec<Payload> =
-                    if let Ok(cached) = tokio::fs::read(cachedir.join(&hash)).await {
-                        debug!("reading from cache");
-                        let cached = unsafe { String::from_utf8_unchecked(cached) };
-                        let cached = cached.lines().map(|x| x.to_owned().into()).collect();
-                        trace!("perf: split into lines ('deserialize')");
-                        cached
-                    } else {
-                        let file = match md {
-                            // TODO: Handle non edifact
-                            Module::MessageIngestion { schema, .. } => schema.file(),
-                            Module::FileIngestion { schema, .. } => schema.records.file(),
-                            _ => panic!(
-                                "Expected first module to be MessageIngestion or FileIngestion "
-                            ),
-                        };
-                        debug!("calling anonymization tool");
-                        let res = call_anon(&s, &root.join("grammar").join(file)).await;
-                        debug!("Anonymization tool output {} lines", res.len());
-
-                        assert_eq!(
-                            res.len(),
-                            lines.len(),
-                            "Line mismatch: anonymization gave \n{res:?}\n\ninput was \n{lines:?}"
-                        );
-
-                        let cache = res.iter().join("\n");
-                        trace!("perf: create output string");
-                        tokio::spawn(async move {
-                            tokio::fs::write(cachedir.join(hash), cache).await.unwrap();
-                            trace!("perf: wrote to cache");
-                        })
-                        .await;
-
-                        res
-                    };
+    zone: TimeZone,
+    out: Sender<Message>,
+) -> eyre::Result<Ingested> {
+    let (file, event_time, select) = match md {
+        // TODO: Handle non edifact
+        Module::MessageIngestion {
+            schema,
+            event_time,
+            select,
+            ..
+        } => (schema.file(), event_time, select),
+        Module::FileIngestion {
+            schema,
+            event_time,
+            select,
+            ..
+        } => (schema.records.file(), event_time, select),
+        _ => eyre::bail!(
+            "Expected first module to be MessageIngestion or FileIngestion, got {}",
+            md.kind()
+        ),
+    };
+    let grammar = root.join("grammar").join(file);
+    let regex = opts
+        .regex
+        .as_deref()
+        .map(Regex::from_str)
+        .transpose()
+        .wrap_err("invalid ingestion regex")?;
+    let event_time = event_time
+        .as_ref()
+        .map(|x| EventTime::new(x, zone))
+        .transpose()
+        .map_err(|e| eyre::eyre!("invalid eventTime of {}: {e}", md.name()))?;
+    let select =
+        Selection::new(select).map_err(|e| eyre::eyre!("invalid select of {}: {e}", md.name()))?;
+    let cache = Cache::open(&opts.cache)?;
+    // Everything that changes the output of the tool besides the input goes in the cache key
+    let grammar_bytes = fs::read(&grammar)
+        .await
+        .wrap_err_with(|| format!("reading grammar {grammar:?}"))?;
+    let tool = cache::tool_fingerprint(&opts.anonymizer).await;
+    let ctx = InputCtx {
+        opts: &opts,
+        regex: regex.as_ref(),
+        grammar: &grammar,
+        grammar_bytes: &grammar_bytes,
+        tool: &tool,
+        cache: &cache,
+        event_time: event_time.as_ref(),
+        select: (!select.add.is_empty() || !select.del.is_empty()).then_some(&select),
+    };
 
-                let ret = inner
-                    .into_iter()
-                    .map(|inner| {
-                        let date = metadata
-                            .clone()
-                            .map_or(IString::from(""), |x| x.process_date.into());
-                        (inner, date)
-                    })
-                    .collect_vec();
-                trace!("perf: attach dates");
-                ret
-            }
-        },
-    );
-    let res = futures::future::join_all(res).await;
-
-    let t = Instant::now();
-    let res = res
-        .into_iter()
-        .flatten()
-        .map(|(payload, date)| {
-            let inner = MessageInner {
-                payload,
-                billingmediation: Default::default(),
+    let mut ret = Ingested {
+        messages: 0,
+        rejects: vec![],
+        anonymizer_time: Duration::ZERO,
+        filtered: 0,
+    };
+    let mut readers = HashMap::new();
+    for index in 0..input.len() {
+        let lines = match readers.remove(&index) {
+            Some(lines) => lines,
+            None => read_input(&input, index, &mut readers),
+        };
+        ingest_input(input[index].clone(), index, lines, &ctx, &out, &mut ret).await?;
+    }
+    Ok(ret)
+}
+
+type Lines = Receiver<std::io::Result<String>>;
+
+/// Starts reading `inputs[index]`. The members of a tar archive that follow each other are read
+/// in one pass, the lines of the ones after `index` go to `readers`.
+fn read_input(inputs: &[Input], index: usize, readers: &mut HashMap<usize, Lines>) -> Lines {
+    let Input { path, encoding, .. } = &inputs[index];
+    if let Some((archive, _)) = crate::input::tar_member(path) {
+        let mut members = vec![];
+        let mut first = None;
+        for (i, input) in inputs.iter().enumerate().skip(index) {
+            let Some((_, member)) =
+                crate::input::tar_member(&input.path).filter(|(x, _)| *x == archive)
+            else {
+                break;
             };
+            let (tx, rx) = channel(LINES_CAP);
+            members.push((member.to_owned(), input.encoding, tx));
+            match first {
+                None => first = Some(rx),
+                Some(_) => _ = readers.insert(i, rx),
+            }
+        }
+        let archive = archive.to_owned();
+        tokio::task::spawn_blocking(move || crate::input::read_tar_members(&archive, members));
+        return first.expect("inputs[index] is a member");
+    }
 
-            Message { inner, date }
-        })
-        .collect();
-    // PERF: This should be almost instant.
-    trace!("perf: create messages");
-    res
-}
-
-const ANON_PATH: &str = "deps/anonymization.jar";
-async fn call_anon(input: &str, grammar: &Path) -> Vec<Payload> {
-    let nul = if cfg!(windows) {
-        "NUL"
-    } else if cfg!(unix) {
-        "/dev/null"
-    } else {
-        panic!("unsupported system")
+    // Decompression and archives are synchronous
+    let (tx, rx) = channel(LINES_CAP);
+    let (path, encoding) = (path.clone(), *encoding);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = crate::input::read_lines(&path, encoding, &tx) {
+            _ = tx.blocking_send(Err(e));
+        }
+    });
+    rx
+}
+
+struct InputCtx<'a> {
+    opts: &'a IngestionOpts,
+    regex: Option<&'a Regex>,
+    grammar: &'a Path,
+    grammar_bytes: &'a [u8],
+    tool: &'a str,
+    cache: &'a Cache,
+    event_time: Option<&'a EventTime>,
+    select: Option<&'a Selection>,
+}
+
+type Line = (usize, String);
+
+struct LineFilter<'a> {
+    head: usize,
+    tail: usize,
+    regex: Option<&'a Regex>,
+    started: bool,
+    blanks: Vec<Line>,
+    pending: VecDeque<Line>,
+}
+
+impl<'a> LineFilter<'a> {
+    fn new(opts: &IngestionOpts, regex: Option<&'a Regex>) -> Self {
+        Self {
+            head: opts.head as usize,
+            tail: opts.tail as usize,
+            regex,
+            started: false,
+            blanks: vec![],
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, line: Line, out: &mut Vec<Line>) {
+        if line.1.trim().is_empty() {
+            if self.started {
+                self.blanks.push(line);
+            }
+            return;
+        }
+        self.started = true;
+        for line in std::mem::take(&mut self.blanks).into_iter().chain([line]) {
+            if self.head > 0 {
+                self.head -= 1;
+            } else if self.regex.is_none_or(|x| x.is_match(&line.1)) {
+                self.pending.push_back(line);
+                if self.pending.len() > self.tail {
+                    out.extend(self.pending.pop_front());
+                }
+            }
+        }
+    }
+}
+
+const CHUNK_LINES: u64 = 64;
+const MAX_CHUNK_LINES: usize = 4 * CHUNK_LINES as usize;
+
+fn is_chunk_end(line: &str) -> bool {
+    let hash = blake3::hash(line.as_bytes());
+    let hash = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+    hash % CHUNK_LINES == 0
+}
+
+/// Where the chunks of `lines` end. Chunks only depend on the lines in them, so inserting or
+/// deleting a line changes the chunk it's in and leaves the others, and their cache, alone.
+fn chunk_ends(lines: &[Line]) -> Vec<usize> {
+    let mut ends = vec![];
+    let mut start = 0;
+    for (i, (_, line)) in lines.iter().enumerate() {
+        if is_chunk_end(line) || i + 1 - start == MAX_CHUNK_LINES {
+            ends.push(i + 1);
+            start = i + 1;
+        }
+    }
+    if start != lines.len() {
+        ends.push(lines.len());
+    }
+    ends
+}
+
+/// Reads an input in batches of at least `size` lines, or all at once when `size` is 0. Batches
+/// end with a chunk, so that their chunks are the same whatever comes before them.
+struct Batches<'a> {
+    lines: Lines,
+    n: usize,
+    filter: LineFilter<'a>,
+    ready: Vec<Line>,
+    scanned: usize,
+    chunk: usize,
+    eof: bool,
+    size: usize,
+}
+
+impl Batches<'_> {
+    async fn next(&mut self) -> std::io::Result<Option<Vec<Line>>> {
+        let mut len = None;
+        while len.is_none() && !self.eof {
+            match self.lines.recv().await {
+                Some(line) => {
+                    self.n += 1;
+                    self.filter.push((self.n, line?), &mut self.ready);
+                    len = self.cut();
+                }
+                None => self.eof = true,
+            }
+        }
+        let len = len.unwrap_or(self.ready.len());
+        self.scanned -= len.min(self.scanned);
+        self.chunk -= len.min(self.chunk);
+        Ok((len != 0).then(|| self.ready.drain(..len).collect()))
+    }
+
+    fn cut(&mut self) -> Option<usize> {
+        while self.scanned < self.ready.len() {
+            self.scanned += 1;
+            let line = &self.ready[self.scanned - 1].1;
+            if is_chunk_end(line) || self.scanned - self.chunk == MAX_CHUNK_LINES {
+                self.chunk = self.scanned;
+                if self.size != 0 && self.scanned >= self.size {
+                    return Some(self.scanned);
+                }
+            }
+        }
+        None
+    }
+}
+
+struct Batch {
+    messages: Vec<Message>,
+    rejects: Vec<Reject>,
+    anonymizer_time: Duration,
+    filtered: u64,
+}
+
+async fn ingest_input(
+    Input {
+        path: input,
+        metadata,
+        ..
+    }: Input,
+    index: usize,
+    lines: Lines,
+    ctx: &InputCtx<'_>,
+    out: &Sender<Message>,
+    ret: &mut Ingested,
+) -> eyre::Result<()> {
+    debug!("starting ingestion from {input}");
+    trace!("perf: ingestion start");
+    let mut batches = Batches {
+        lines,
+        n: 0,
+        filter: LineFilter::new(ctx.opts, ctx.regex),
+        ready: vec![],
+        scanned: 0,
+        chunk: 0,
+        eof: false,
+        size: ctx.opts.batch_size as usize,
     };
+    let date = metadata.map_or(IString::from(""), |x| x.process_date.into());
 
-    let grammar = grammar.as_os_str().to_string_lossy();
-    let grammar = &grammar;
-    let args = [
-        &format!("-DlogFile={nul}"),
-        "-jar",
-        ANON_PATH,
-        "edidumpjson",
-        "--input-file",
-        "-",
-        "--grammar-file",
+    // The next batches are anonymized while the current one is sent, in order
+    let mut in_flight = FuturesOrdered::new();
+    let mut done = false;
+    let mut messages = 0;
+    let mut rejects = 0;
+    loop {
+        while !done && in_flight.len() < BATCHES_IN_FLIGHT {
+            match batches
+                .next()
+                .await
+                .wrap_err_with(|| format!("reading input {input}"))?
+            {
+                Some(lines) => {
+                    in_flight.push_back(anonymize_batch(lines, &input, index, &date, ctx))
+                }
+                None => done = true,
+            }
+        }
+        let Some(batch) = in_flight.next().await else {
+            break;
+        };
+        let batch = batch?;
+        messages += batch.messages.len();
+        rejects += batch.rejects.len();
+        ret.messages += batch.messages.len() as u64;
+        ret.rejects.extend(batch.rejects);
+        ret.anonymizer_time += batch.anonymizer_time;
+        ret.filtered += batch.filtered;
+        for msg in batch.messages {
+            if out.send(msg).await.is_err() {
+                warn!("the pipeline stopped before the end of {input}");
+                return Ok(());
+            }
+        }
+    }
+    debug!("got {messages} messages from {input}");
+    if rejects != 0 {
+        warn!("{rejects} lines of {input} couldn't be decoded");
+    }
+    Ok(())
+}
+
+async fn anonymize_batch(
+    lines: Vec<Line>,
+    input: &str,
+    index: usize,
+    date: &IString,
+    ctx: &InputCtx<'_>,
+) -> eyre::Result<Batch> {
+    let InputCtx {
+        opts,
         grammar,
-    ];
-    debug!("{args:?}");
-    let mut child = Command::new("java")
-        .args(args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .unwrap();
+        cache,
+        ..
+    } = *ctx;
+    let (first, last) = (lines[0].0, lines[lines.len() - 1].0);
+    let file = IString::from(input);
 
-    child
-        .stdin
-        .as_mut()
-        .unwrap()
-        .write_all(input.as_bytes())
-        .await
-        .unwrap();
+    // The output is cached in content defined chunks, so that editing an input only anonymizes
+    // the chunks that changed
+    let mut inner: Vec<Option<AnonLine>> = vec![None; lines.len()];
+    let mut missing = vec![];
+    let mut start = 0;
+    for end in chunk_ends(&lines) {
+        let text = lines[start..end].iter().map(|x| &x.1).join("\n");
+        let key = Cache::key(&text, ctx.grammar_bytes, ctx.tool);
+        match cache.get(&key).await {
+            Some(cached) if cached.lines().count() == end - start => {
+                for (slot, line) in inner[start..end].iter_mut().zip(cached.lines()) {
+                    *slot = Some(Ok(line.to_owned().into()));
+                }
+            }
+            _ => missing.push((start..end, key)),
+        }
+        start = end;
+    }
+    trace!("perf: hash messages");
 
-    let stdout = child.wait_with_output().await.unwrap().stdout;
-    trace!("Anonymization tool returned {} bytes", stdout.len());
-    stdout
-        .trim_ascii()
-        .par_split(|x| *x == b'\n')
-        .inspect(|x| assert_ne!(x.trim_ascii(), b"{  }", "Line was empty"))
-        // Safety: Edifact is guaranteed to be valid ascii
-        .map(|x| unsafe { std::str::from_utf8_unchecked(x).to_owned().into() })
-        .collect()
+    let mut anonymizer_time = Duration::ZERO;
+    if missing.is_empty() {
+        debug!("reading lines {first}-{last} of {input} from cache");
+    } else {
+        // The chunks that missed are anonymized together
+        let todo = missing.iter().flat_map(|(range, _)| &lines[range.clone()]);
+        let s = todo.map(|x| &x.1).join("\n");
+        let count = missing.iter().map(|(range, _)| range.len()).sum();
+        debug!("calling anonymization tool on {count} of lines {first}-{last} of {input}");
+        let t = Instant::now();
+        let res = anonymizer::anonymize(&s, count, grammar, &opts.anonymizer)
+            .await
+            .wrap_err_with(|| {
+                format!("anonymizing lines {first}-{last} of {input} with grammar {grammar:?}")
+            })?;
+        anonymizer_time = t.elapsed();
+        debug!("Anonymization tool output {} lines", res.len());
+
+        let mut res = res.into_iter();
+        for (range, key) in missing {
+            let chunk = res.by_ref().take(range.len()).collect_vec();
+            // Chunks with lines that failed aren't cached so that they are retried next time
+            if let Ok(ok) = chunk
+                .iter()
+                .map(|x| x.as_deref())
+                .collect::<Result<Vec<_>, _>>()
+            {
+                let (from, to) = (lines[range.start].0, lines[range.end - 1].0);
+                let source = format!("{input}:{from}-{to}");
+                cache
+                    .put(&key, &ok.iter().join("\n"), &source, grammar)
+                    .await;
+            }
+            for (slot, x) in inner[range].iter_mut().zip(chunk) {
+                *slot = Some(x);
+            }
+        }
+    }
+    // Every chunk was either cached or anonymized
+    let inner = inner.into_iter().map(Option::unwrap);
+
+    let (messages, rejects): (Vec<_>, Vec<_>) = inner
+        .into_iter()
+        .zip(lines)
+        .map(|(payload, (line, text))| {
+            let payload = payload.and_then(|x| match undecodable(&x) {
+                None => Ok(x),
+                Some(reason) => Err(reason.to_owned()),
+            });
+            let msg = payload.map(|payload| Message {
+                inner: MessageInner {
+                    payload,
+                    billingmediation: Default::default(),
+                },
+                date: date.clone(),
+                source: Some(Source {
+                    file: file.clone(),
+                    line,
+                    index,
+                }),
+            });
+            let keep = match (&msg, ctx.event_time) {
+                (Ok(msg), Some(event_time)) => event_time
+                    .contains(msg)
+                    .map_err(|e| format!("can't read the event time: {e}")),
+                _ => Ok(true),
+            };
+            // After the event time, which the selection may remove
+            let msg = match (
+                msg.and_then(|msg| keep.map(|keep| keep.then_some(msg))),
+                ctx.select,
+            ) {
+                (Ok(Some(mut msg)), Some(select)) => select
+                    .apply(&mut msg)
+                    .map(|()| Some(msg))
+                    .map_err(|e| format!("can't apply the select: {e}")),
+                (msg, _) => msg,
+            };
+            match msg {
+                Ok(msg) => Ok(msg),
+                Err(reason) => Err(Reject {
+                    file: input.to_owned(),
+                    line,
+                    index,
+                    grammar: grammar.to_owned(),
+                    reason,
+                    input: text,
+                }),
+            }
+        })
+        .partition_result();
+    trace!("perf: attach dates");
+    let filtered = messages.iter().filter(|x| x.is_none()).count() as u64;
+
+    Ok(Batch {
+        messages: messages.into_iter().flatten().collect(),
+        rejects,
+        anonymizer_time,
+        filtered,
+    })
+}
+
+pub fn expand_inputs(inputs: Vec<Input>) -> eyre::Result<Vec<Input>> {
+    let mut ret = vec![];
+    for input in inputs {
+        let pattern = input
+            .date_pattern
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .wrap_err_with(|| format!("invalid date_pattern for input {}", input.path))?;
+        let mut paths = if Path::new(&input.path).is_dir() {
+            std::fs::read_dir(&input.path)
+                .and_then(|x| x.map_ok(|x| x.path()).collect::<Result<Vec<_>, _>>())
+                .wrap_err_with(|| format!("listing input {}", input.path))?
+                .into_iter()
+                .filter(|x| x.is_file())
+                .collect_vec()
+        } else if input.path.contains(['*', '?', '[']) {
+            glob::glob(&input.path)
+                .wrap_err_with(|| format!("invalid glob {}", input.path))?
+                .collect::<Result<Vec<_>, _>>()
+                .wrap_err_with(|| format!("listing input {}", input.path))?
+                .into_iter()
+                .filter(|x| x.is_file())
+                .collect_vec()
+        } else {
+            vec![PathBuf::from(&input.path)]
+        };
+        paths.sort();
+        if paths.is_empty() {
+            eyre::bail!("input {} matched no files", input.path);
+        }
+        debug!("input {} is {} files", input.path, paths.len());
+        let mut names = vec![];
+        for path in paths {
+            if !crate::input::is_archive(&path) {
+                names.push(path.to_string_lossy().into_owned());
+                continue;
+            }
+            let members = crate::input::archive_members(&path)
+                .wrap_err_with(|| format!("listing the archive {path:?}"))?;
+            debug!("archive {path:?} has {} members", members.len());
+            let path = path.to_string_lossy();
+            names.extend(
+                members
+                    .into_iter()
+                    .map(|x| format!("{path}{}{x}", crate::input::MEMBER_SEP)),
+            );
+        }
+
+        for name in names {
+            let date = pattern
+                .as_ref()
+                .and_then(|x| date_from_name(x, Path::new(&name)));
+            if pattern.is_some() && date.is_none() {
+                warn!("no date in the name of {name}");
+            }
+            ret.push(Input {
+                path: name,
+                metadata: date
+                    .map(|process_date| InputMetadata { process_date })
+                    .or(input.metadata.clone()),
+                date_pattern: None,
+                encoding: input.encoding,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+/// Process date, in the format of the configs, from the name of `path`. It has no offset, the
+/// driver reads it in the `time_zone` of the config.
+fn date_from_name(pattern: &Regex, path: &Path) -> Option<String> {
+    let caps = pattern.captures(path.file_name()?.to_str()?)?;
+    let num = |x| caps.name(x).map_or(Some(0), |x| x.as_str().parse().ok());
+    let year: i16 = caps.name("y")?.as_str().parse().ok()?;
+    let date = Civil::new(
+        if year < 100 { 2000 + year } else { year },
+        num("m")?,
+        num("d")?,
+        num("H")?,
+        num("M")?,
+        num("S")?,
+        0,
+    )
+    .ok()?;
+    Some(date.strftime("%Y-%m-%dT%H:%M:%S").to_string())
+}
+
+/// The anonymization tool writes an empty object for lines it couldn't decode
+fn undecodable(payload: &str) -> Option<&'static str> {
+    let payload = payload.trim();
+    if !payload.starts_with('{') || !payload.ends_with('}') {
+        Some("anonymization tool output is not a json object")
+    } else if payload[1..payload.len() - 1].trim().is_empty() {
+        Some("anonymization tool couldn't decode the line")
+    } else {
+        None
+    }
+}
+
+pub async fn load_saved(
+    path: &Path,
+    route: Option<&str>,
+    date: IString,
+    out: Sender<Message>,
+) -> eyre::Result<Ingested> {
+    let path = path.to_owned();
+    let route = route.map(str::to_owned);
+    let read = tokio::task::spawn_blocking(move || {
+        let (mut messages, mut rejects, mut n) = (0, vec![], 0);
+        let res = crate::output::for_each_record(&path, |record| {
+            n += 1;
+            match MessageInner::from_with_source(&record) {
+                Ok((inner, reserved))
+                    if route
+                        .as_deref()
+                        .is_some_and(|route| match &reserved.stream {
+                            Some(stream) => stream != route,
+                            None => !was_routed(&inner, route),
+                        }) =>
+                {
+                    true
+                }
+                // Messages keep pointing at the input line they came from
+                Ok((inner, Reserved { source, .. })) => {
+                    messages += 1;
+                    let msg = Message {
+                        inner,
+                        date: date.clone(),
+                        source,
+                    };
+                    out.blocking_send(msg).is_ok()
+                }
+                Err(reason) => {
+                    rejects.push(Reject {
+                        file: path.to_string_lossy().into_owned(),
+                        line: n,
+                        index: 0,
+                        grammar: PathBuf::new(),
+                        reason,
+                        input: record,
+                    });
+                    true
+                }
+            }
+        });
+        res.wrap_err_with(|| format!("reading {path:?}"))?;
+        debug!("loaded {messages} messages from {path:?}");
+        Ok(Ingested {
+            messages,
+            rejects,
+            anonymizer_time: Duration::ZERO,
+            filtered: 0,
+        })
+    });
+    read.await
+        .wrap_err("the task reading saved messages panicked")?
+}
+
+fn was_routed(inner: &MessageInner, route: &str) -> bool {
+    use serde_json::Value;
+    let bm = &inner.billingmediation;
+    if bm.contains_key("error") {
+        return route == "error";
+    }
+    match bm.get("route") {
+        None | Some(Value::Null) => route == "output",
+        Some(Value::String(s)) => s == route,
+        Some(Value::Array(arr)) => arr.iter().any(|x| x.as_str() == Some(route)),
+        Some(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunks_survive_insertions() {
+        let chunks = |lines: &[Line]| {
+            let mut start = 0;
+            chunk_ends(lines)
+                .into_iter()
+                .map(|end| {
+                    let chunk = lines[start..end].iter().map(|x| &x.1).join("\n");
+                    start = end;
+                    chunk
+                })
+                .collect_vec()
+        };
+        let mut lines = (0..2000).map(|n| (n, format!("UNH+{n}'"))).collect_vec();
+        let before = chunks(&lines);
+        lines.insert(10, (0, "UNH+new'".to_owned()));
+        let after = chunks(&lines);
+
+        assert!(before.len() > 10);
+        assert!(before.iter().all(|x| x.lines().count() <= MAX_CHUNK_LINES));
+        // Only the first chunk changed
+        assert_eq!(before[1..], after[1..]);
+    }
+
+    #[test]
+    fn expands_directories_globs_and_archives() {
+        let dir = std::env::temp_dir().join(format!("inputs-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        for name in [
+            "PPT.EOT.BGC.D240402.T120000",
+            "PPT.EOT.BGC.D240401.T000922",
+            "PPT.EOT.BGC.D240230.T000000",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "UNH'").unwrap();
+        }
+        let mut tar = tar::Builder::new(std::fs::File::create(dir.join("day.tar")).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        for name in ["PPT.EOT.BGC.D240403.T000000", "a.txt"] {
+            header.set_cksum();
+            tar.append_data(&mut header, name, &b"UNH'"[..]).unwrap();
+        }
+        tar.finish().unwrap();
+
+        let input = |path: String| Input {
+            path,
+            metadata: Some(InputMetadata {
+                process_date: "2024-01-01".to_owned(),
+            }),
+            date_pattern: Some(
+                r"D(?<y>\d{2})(?<m>\d{2})(?<d>\d{2})\.T(?<H>\d{2})(?<M>\d{2})(?<S>\d{2})"
+                    .to_owned(),
+            ),
+            encoding: Default::default(),
+        };
+        let root = dir.to_string_lossy().into_owned();
+        let expanded = expand_inputs(vec![input(root.clone())]).unwrap();
+        let got = expanded
+            .iter()
+            .map(|x| {
+                let name = x.path.strip_prefix(&root).unwrap().to_owned();
+                (name, x.metadata.as_ref().unwrap().process_date.clone())
+            })
+            .collect_vec();
+        let expected = [
+            ("/PPT.EOT.BGC.D240230.T000000", "2024-01-01"),
+            ("/PPT.EOT.BGC.D240401.T000922", "2024-04-01T00:09:22"),
+            ("/PPT.EOT.BGC.D240402.T120000", "2024-04-02T12:00:00"),
+            (
+                "/day.tar!PPT.EOT.BGC.D240403.T000000",
+                "2024-04-03T00:00:00",
+            ),
+            ("/day.tar!a.txt", "2024-01-01"),
+            ("/notes.txt", "2024-01-01"),
+        ];
+        let expected = expected.map(|(a, b)| (a.to_owned(), b.to_owned()));
+        assert_eq!(got, expected);
+
+        let glob = expand_inputs(vec![input(format!("{root}/PPT.*"))]).unwrap();
+        assert_eq!(glob.len(), 3);
+        assert!(expand_inputs(vec![input(format!("{root}/nothing*"))]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_outputs_reject_broken_lines() {
+        let path = std::env::temp_dir().join(format!("saved-{}.jsonl", std::process::id()));
+        let lines = [
+            r#"{"a":1,"billingmediation":{}}"#,
+            r#"{"a":2,"billingmed"#,
+            r#"{"a":3,"billingmediation":{"route":"other"}}"#,
+            // The stream the message was saved on wins
+            r#"{"a":4,"billingmediation":{"route":"other"},"_stream":"output"}"#,
+            r#"{"a":5,"billingmediation":{},"_stream":"error"}"#,
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let (tx, mut rx) = channel(8);
+        let saved = load_saved(&path, Some("output"), "".into(), tx)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved.messages, 2);
+        assert_eq!(rx.recv().await.unwrap().inner.to_string(), lines[0]);
+        assert_eq!(
+            rx.recv().await.unwrap().inner.to_string(),
+            r#"{"a":4,"billingmediation":{"route":"other"}}"#
+        );
+        assert_eq!(saved.rejects.len(), 1);
+        assert_eq!(saved.rejects[0].line, 2);
+    }
+
+    #[test]
+    fn select_adds_then_deletes() {
+        let select = |items: serde_json::Value| {
+            Selection::new(&serde_json::from_value::<Vec<FieldSelection>>(items).unwrap())
+        };
+        let apply = |select: &Selection, payload: &str| {
+            let mut msg = Message {
+                inner: MessageInner::from(payload.to_owned()),
+                date: Default::default(),
+                source: None,
+            };
+            select
+                .apply(&mut msg)
+                .map(|()| msg.inner.payload.to_string())
+        };
+
+        let both = select(serde_json::json!([
+            {"add": "a.b"}, {"add": "c"}, {"add": "missing"}, {"del": "a.b.x"}
+        ]))
+        .unwrap();
+        assert_eq!(
+            apply(&both, r#"{"a":{"b":{"x":1,"y":2},"z":3},"c":[4],"d":5}"#).unwrap(),
+            r#"{"a":{"b":{"y":2}},"c":[4]}"#
+        );
+        let del = select(serde_json::json!([{"del": "d"}, {"del": "e.f"}])).unwrap();
+        assert_eq!(apply(&del, r#"{"c":4,"d":5}"#).unwrap(), r#"{"c":4}"#);
+        assert!(apply(&both, "{").is_err());
+        assert!(select(serde_json::json!([{"add": "billingmediation.route"}])).is_err());
+        assert!(
+            serde_json::from_value::<Vec<FieldSelection>>(serde_json::json!([{"keep": "a"}]))
+                .is_err()
+        );
+    }
 }
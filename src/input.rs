@@ -0,0 +1,356 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+use tokio::sync::mpsc::Sender;
+
+use crate::opts::Encoding;
+
+pub const MEMBER_SEP: char = '!';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Archive {
+    Zip,
+    Tar,
+}
+
+fn archive_kind(path: &Path) -> Option<Archive> {
+    let name = path.file_name()?.to_str()?;
+    if name.ends_with(".zip") {
+        Some(Archive::Zip)
+    } else if [".tar", ".tar.gz", ".tgz", ".tar.zst", ".tar.bz2"]
+        .iter()
+        .any(|x| name.ends_with(x))
+    {
+        Some(Archive::Tar)
+    } else {
+        None
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    archive_kind(path).is_some()
+}
+
+fn split_member(input: &str) -> Option<(&Path, &str)> {
+    input
+        .match_indices(MEMBER_SEP)
+        .map(|(i, _)| (Path::new(&input[..i]), &input[i + 1..]))
+        .find(|(archive, _)| is_archive(archive) && archive.is_file())
+}
+
+fn decompress<'a>(name: &str, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(flate2::read::MultiGzDecoder::new(reader))
+    } else if name.ends_with(".zst") {
+        Box::new(zstd::Decoder::new(reader)?)
+    } else if name.ends_with(".bz2") {
+        Box::new(bzip2::read::MultiBzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    })
+}
+
+fn open_tar(path: &Path) -> io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = File::open(path)?;
+    Ok(tar::Archive::new(decompress(
+        &path.to_string_lossy(),
+        file,
+    )?))
+}
+
+pub fn archive_members(path: &Path) -> io::Result<Vec<String>> {
+    let mut ret = vec![];
+    match archive_kind(path) {
+        Some(Archive::Zip) => {
+            let mut zip = zip::ZipArchive::new(File::open(path)?)?;
+            for i in 0..zip.len() {
+                let member = zip.by_index_raw(i)?;
+                if member.is_file() {
+                    ret.push(member.name().to_owned());
+                }
+            }
+        }
+        Some(Archive::Tar) => {
+            for entry in open_tar(path)?.entries()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    ret.push(entry.path()?.to_string_lossy().into_owned());
+                }
+            }
+        }
+        None => {}
+    }
+    Ok(ret)
+}
+
+pub fn read_lines(
+    input: &str,
+    encoding: Encoding,
+    tx: &Sender<io::Result<String>>,
+) -> io::Result<()> {
+    let send = |reader: &mut dyn Read| {
+        decode_lines(BufReader::new(reader), encoding, |line| {
+            tx.blocking_send(Ok(line)).is_ok()
+        })
+    };
+    let Some((archive, member)) = split_member(input) else {
+        send(&mut decompress(input, File::open(input)?)?)?;
+        return Ok(());
+    };
+    let not_found = || {
+        let msg = format!("no {member} in {archive:?}");
+        io::Error::new(io::ErrorKind::NotFound, msg)
+    };
+    match archive_kind(archive) {
+        Some(Archive::Zip) => {
+            let mut zip = zip::ZipArchive::new(File::open(archive)?)?;
+            let file = zip.by_name(member).map_err(|_| not_found())?;
+            send(&mut decompress(member, file)?)?;
+        }
+        Some(Archive::Tar) => {
+            read_tar_members(archive, vec![(member.to_owned(), encoding, tx.clone())]);
+        }
+        None => unreachable!("split_member only returns archives"),
+    }
+    Ok(())
+}
+
+/// The archive and the member of `input` when it's a member of a tar archive. Unlike zip ones,
+/// tar archives can only be read from the start, see [`read_tar_members`].
+pub fn tar_member(input: &str) -> Option<(&Path, &str)> {
+    split_member(input).filter(|(archive, _)| archive_kind(archive) == Some(Archive::Tar))
+}
+
+pub type TarMember = (String, Encoding, Sender<io::Result<String>>);
+
+/// Sends the lines of the `members` of a tar archive, each to its own sender, which is dropped
+/// once its member is done. Members in the order they are stored in are read in one pass over
+/// the archive, each one out of order takes one more. Errors go to the senders. Blocks.
+pub fn read_tar_members(archive: &Path, members: Vec<TarMember>) {
+    let mut members = members.into_iter().peekable();
+    while members.peek().is_some() {
+        let mut found = false;
+        let mut pass = || -> io::Result<()> {
+            for entry in open_tar(archive)?.entries()? {
+                let Some((member, encoding, tx)) = members.peek() else {
+                    break;
+                };
+                let entry = entry?;
+                if entry.path()?.to_string_lossy() != *member {
+                    continue;
+                }
+                found = true;
+                let res = decompress(member, entry).and_then(|reader| {
+                    decode_lines(BufReader::new(reader), *encoding, |line| {
+                        tx.blocking_send(Ok(line)).is_ok()
+                    })
+                });
+                if let Err(e) = res {
+                    _ = tx.blocking_send(Err(e));
+                }
+                members.next();
+            }
+            Ok(())
+        };
+        if let Err(e) = pass() {
+            for (_, _, tx) in members {
+                _ = tx.blocking_send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+            return;
+        }
+        // Nothing was read, the next member isn't in there
+        if !found {
+            if let Some((member, _, tx)) = members.next() {
+                let msg = format!("no {member} in {archive:?}");
+                _ = tx.blocking_send(Err(io::Error::new(io::ErrorKind::NotFound, msg)));
+            }
+        }
+    }
+}
+
+fn decode_lines(
+    mut reader: impl BufRead,
+    encoding: Encoding,
+    mut f: impl FnMut(String) -> bool,
+) -> io::Result<()> {
+    let table = match encoding {
+        Encoding::Utf8 | Encoding::Latin1 => None,
+        Encoding::Cp037 => Some(&CP037),
+        Encoding::Cp500 => Some(&CP500),
+    };
+    // EBCDIC has both LF and NL, and which one ends lines depends on where the file comes from
+    let is_newline = |b: u8| match table {
+        None => b == b'\n',
+        Some(_) => b == 0x25 || b == 0x15,
+    };
+    let decode = |line: &[u8], offset: usize, n: usize| -> io::Result<String> {
+        let mut line = match table {
+            Some(table) => line
+                .iter()
+                .map(|&b| char::from(table[b as usize]))
+                .collect(),
+            None if encoding == Encoding::Latin1 => line.iter().map(|&b| char::from(b)).collect(),
+            None => String::from_utf8(line.to_owned()).map_err(|e| {
+                let at = offset + e.utf8_error().valid_up_to();
+                let msg = format!("invalid utf-8 at byte {at} (line {n}), is the encoding right?");
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?,
+        };
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        Ok(line)
+    };
+
+    let mut line = vec![];
+    // Byte offset of the start of `line` and its 1-based number
+    let mut offset = 0;
+    let mut n = 1;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let (len, done) = match buf.iter().position(|&b| is_newline(b)) {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        line.extend_from_slice(&buf[..len - done as usize]);
+        reader.consume(len);
+        if done {
+            if !f(decode(&line, offset, n)?) {
+                return Ok(());
+            }
+            offset += line.len() + 1;
+            n += 1;
+            line.clear();
+        }
+    }
+    if !line.is_empty() {
+        f(decode(&line, offset, n)?);
+    }
+    Ok(())
+}
+
+/// EBCDIC to Latin-1, from the Unicode mapping of code page 037
+#[rustfmt::skip]
+static CP037: [u8; 256] = [
+    0x00, 0x01, 0x02, 0x03, 0x9c, 0x09, 0x86, 0x7f, 0x97, 0x8d, 0x8e, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x9d, 0x85, 0x08, 0x87, 0x18, 0x19, 0x92, 0x8f, 0x1c, 0x1d, 0x1e, 0x1f,
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x0a, 0x17, 0x1b, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x05, 0x06, 0x07,
+    0x90, 0x91, 0x16, 0x93, 0x94, 0x95, 0x96, 0x04, 0x98, 0x99, 0x9a, 0x9b, 0x14, 0x15, 0x9e, 0x1a,
+    0x20, 0xa0, 0xe2, 0xe4, 0xe0, 0xe1, 0xe3, 0xe5, 0xe7, 0xf1, 0xa2, 0x2e, 0x3c, 0x28, 0x2b, 0x7c,
+    0x26, 0xe9, 0xea, 0xeb, 0xe8, 0xed, 0xee, 0xef, 0xec, 0xdf, 0x21, 0x24, 0x2a, 0x29, 0x3b, 0xac,
+    0x2d, 0x2f, 0xc2, 0xc4, 0xc0, 0xc1, 0xc3, 0xc5, 0xc7, 0xd1, 0xa6, 0x2c, 0x25, 0x5f, 0x3e, 0x3f,
+    0xf8, 0xc9, 0xca, 0xcb, 0xc8, 0xcd, 0xce, 0xcf, 0xcc, 0x60, 0x3a, 0x23, 0x40, 0x27, 0x3d, 0x22,
+    0xd8, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0xab, 0xbb, 0xf0, 0xfd, 0xfe, 0xb1,
+    0xb0, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0xaa, 0xba, 0xe6, 0xb8, 0xc6, 0xa4,
+    0xb5, 0x7e, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0xa1, 0xbf, 0xd0, 0xdd, 0xde, 0xae,
+    0x5e, 0xa3, 0xa5, 0xb7, 0xa9, 0xa7, 0xb6, 0xbc, 0xbd, 0xbe, 0x5b, 0x5d, 0xaf, 0xa8, 0xb4, 0xd7,
+    0x7b, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0xad, 0xf4, 0xf6, 0xf2, 0xf3, 0xf5,
+    0x7d, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x52, 0xb9, 0xfb, 0xfc, 0xf9, 0xfa, 0xff,
+    0x5c, 0xf7, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0xb2, 0xd4, 0xd6, 0xd2, 0xd3, 0xd5,
+    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0xb3, 0xdb, 0xdc, 0xd9, 0xda, 0x9f,
+];
+
+/// EBCDIC to Latin-1, from the Unicode mapping of code page 500
+#[rustfmt::skip]
+static CP500: [u8; 256] = [
+    0x00, 0x01, 0x02, 0x03, 0x9c, 0x09, 0x86, 0x7f, 0x97, 0x8d, 0x8e, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x9d, 0x85, 0x08, 0x87, 0x18, 0x19, 0x92, 0x8f, 0x1c, 0x1d, 0x1e, 0x1f,
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x0a, 0x17, 0x1b, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x05, 0x06, 0x07,
+    0x90, 0x91, 0x16, 0x93, 0x94, 0x95, 0x96, 0x04, 0x98, 0x99, 0x9a, 0x9b, 0x14, 0x15, 0x9e, 0x1a,
+    0x20, 0xa0, 0xe2, 0xe4, 0xe0, 0xe1, 0xe3, 0xe5, 0xe7, 0xf1, 0x5b, 0x2e, 0x3c, 0x28, 0x2b, 0x21,
+    0x26, 0xe9, 0xea, 0xeb, 0xe8, 0xed, 0xee, 0xef, 0xec, 0xdf, 0x5d, 0x24, 0x2a, 0x29, 0x3b, 0x5e,
+    0x2d, 0x2f, 0xc2, 0xc4, 0xc0, 0xc1, 0xc3, 0xc5, 0xc7, 0xd1, 0xa6, 0x2c, 0x25, 0x5f, 0x3e, 0x3f,
+    0xf8, 0xc9, 0xca, 0xcb, 0xc8, 0xcd, 0xce, 0xcf, 0xcc, 0x60, 0x3a, 0x23, 0x40, 0x27, 0x3d, 0x22,
+    0xd8, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0xab, 0xbb, 0xf0, 0xfd, 0xfe, 0xb1,
+    0xb0, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0xaa, 0xba, 0xe6, 0xb8, 0xc6, 0xa4,
+    0xb5, 0x7e, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0xa1, 0xbf, 0xd0, 0xdd, 0xde, 0xae,
+    0xa2, 0xa3, 0xa5, 0xb7, 0xa9, 0xa7, 0xb6, 0xbc, 0xbd, 0xbe, 0xac, 0x7c, 0xaf, 0xa8, 0xb4, 0xd7,
+    0x7b, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0xad, 0xf4, 0xf6, 0xf2, 0xf3, 0xf5,
+    0x7d, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x52, 0xb9, 0xfb, 0xfc, 0xf9, 0xfa, 0xff,
+    0x5c, 0xf7, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0xb2, 0xd4, 0xd6, 0xd2, 0xd3, 0xd5,
+    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0xb3, 0xdb, 0xdc, 0xd9, 0xda, 0x9f,
+];
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use super::*;
+
+    fn decode(bytes: &[u8], encoding: Encoding) -> io::Result<Vec<String>> {
+        let mut lines = vec![];
+        decode_lines(bytes, encoding, |x| {
+            lines.push(x);
+            true
+        })?;
+        Ok(lines)
+    }
+
+    #[test]
+    fn encodings() {
+        // "Hello", NL, "A 1", LF, then the brackets of CP500 that are "¢!" in CP037
+        let ebcdic = b"\xc8\x85\x93\x93\x96\x15\xc1\x40\xf1\x25\x4a\x5a";
+        assert_eq!(
+            decode(ebcdic, Encoding::Cp037).unwrap(),
+            ["Hello", "A 1", "¢!"]
+        );
+        assert_eq!(
+            decode(ebcdic, Encoding::Cp500).unwrap(),
+            ["Hello", "A 1", "[]"]
+        );
+        // 0x85 is NEL in Latin-1, not a newline
+        let latin1 = b"caf\xe9\r\n\xa3\x85\n";
+        assert_eq!(
+            decode(latin1, Encoding::Latin1).unwrap(),
+            ["café", "£\u{85}"]
+        );
+        assert_eq!(decode(b"a\r\n\nb", Encoding::Utf8).unwrap(), ["a", "", "b"]);
+
+        let e = decode(b"ok\nstill ok\nbad \xff\n", Encoding::Utf8).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e
+            .to_string()
+            .starts_with("invalid utf-8 at byte 16 (line 3)"));
+    }
+
+    #[test]
+    fn tar_members_in_any_order() {
+        let path = std::env::temp_dir().join(format!("members-{}.tar.gz", std::process::id()));
+        let gz = flate2::write::GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        let mut tar = tar::Builder::new(gz);
+        for name in ["a", "b", "c"] {
+            let data = format!("{name}1\n{name}2\n");
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let mut rxs = vec![];
+        let members = ["a", "c", "b", "z"].map(|name| {
+            let (tx, rx) = channel(16);
+            rxs.push(rx);
+            (name.to_owned(), Encoding::Utf8, tx)
+        });
+        read_tar_members(&path, members.into());
+        std::fs::remove_file(&path).unwrap();
+
+        let mut read = |rx: &mut Receiver<io::Result<String>>| {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .map(|x| x.unwrap_or_else(|e| e.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read(&mut rxs[0]), ["a1", "a2"]);
+        assert_eq!(read(&mut rxs[1]), ["c1", "c2"]);
+        assert_eq!(read(&mut rxs[2]), ["b1", "b2"]);
+        assert!(read(&mut rxs[3])[0].starts_with("no z in"));
+    }
+}
@@ -4,9 +4,9 @@
 
 use std::{
     process::exit,
-    sync::{mpsc, Arc, Once, OnceLock},
+    sync::{Arc, Once, OnceLock},
     thread::{self, sleep, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crossbeam::{
@@ -30,7 +30,7 @@ pub struct TaskData {
     pub data: Message,
     pub module: String,
     pub exported_name: String,
-    pub tx: mpsc::Sender<RetData>, // ok?
+    pub tx: crossbeam::channel::Sender<RetData>,
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +38,7 @@ pub struct RetData {
     pub idx: usize,
     pub data: Message,
     pub module: String,
+    pub js_time: Duration,
 }
 
 pub fn worker_pool(scripts: Vec<String>) -> crossbeam::channel::Sender<TaskData> {
@@ -119,6 +120,7 @@ pub fn handle_message(
     }: TaskData,
     modules: Local<'_, v8::Object>,
 ) {
+    let t0 = Instant::now();
     let scope = &mut v8::HandleScope::new(scope);
     let scope = &mut v8::TryCatch::new(scope);
     let process: Local<'_, v8::Function> = run_script(
@@ -130,10 +132,12 @@ pub fn handle_message(
     .unwrap();
 
     let date = data.date.clone();
+    let source = data.source.clone();
+    let original = data.inner;
     // Serialize into js
 
     // PERF: check if I can build the object separately and that improves perf
-    let data = data.inner.to_string();
+    let data = original.to_string();
     let data = v8::String::new(scope, &data).unwrap();
 
     let data = v8::json::parse(scope, data).unwrap();
@@ -145,10 +149,29 @@ pub fn handle_message(
     // Deserialize from js
     let output = v8::json::stringify(scope, data).unwrap();
     let data = output.to_rust_string_lossy(scope);
-    let inner = MessageInner::from_with_bm(&data);
+    // The rule can leave anything in there, what isn't a message goes to `error` as it came in
+    let inner = MessageInner::from_with_bm(&data).unwrap_or_else(|reason| {
+        let mut inner = original;
+        let bm = &mut inner.billingmediation;
+        bm.insert(
+            "error".to_owned(),
+            format!("invalid rule output: {reason}").into(),
+        );
+        bm.insert("route".to_owned(), "error".into());
+        inner
+    });
 
-    let data = Message { inner, date };
-    let ret_data = RetData { idx, data, module };
+    let data = Message {
+        inner,
+        date,
+        source,
+    };
+    let ret_data = RetData {
+        idx,
+        data,
+        module,
+        js_time: t0.elapsed(),
+    };
     tx.send(ret_data).unwrap();
     // warn!("send {idx}");
 }
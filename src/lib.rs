@@ -2,14 +2,24 @@
 #![feature(byte_slice_trim_ascii)]
 #![feature(try_blocks)]
 #![feature(str_from_raw_parts)]
-#![feature(ptr_sub_ptr)]
 #![feature(anonymous_lifetime_in_impl_trait)]
 
+pub mod aggregation;
+pub mod anonymizer;
+pub mod cache;
+pub mod datapath;
+pub mod datetime;
+pub mod diff;
 pub mod driver;
 pub mod execution;
 pub mod ingestion;
+pub mod input;
 pub mod js;
+pub mod lookup;
 pub mod opts;
+pub mod output;
 pub mod playbook;
+pub mod reporting;
 pub mod schemas;
+pub mod summary;
 pub mod tree;
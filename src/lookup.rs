@@ -0,0 +1,181 @@
+use std::{collections::HashMap, path::Path};
+
+use serde_json::Value;
+
+use crate::{
+    datapath::{self, DataPath, Root},
+    opts::Message,
+    playbook::Module,
+};
+
+/// Tables have no history, so the `eventTime` of the module doesn't change which record matches.
+pub struct Lookup {
+    join: Vec<(DataPath, DataPath)>,
+    lookup: Vec<DataPath>,
+    unique: bool,
+    records: Vec<Value>,
+    index: HashMap<String, Vec<usize>>,
+}
+
+impl Lookup {
+    pub fn new(module: &Module) -> Result<Self, String> {
+        let Module::Lookup {
+            table,
+            join,
+            lookup,
+            expect_unique_match,
+            table_file,
+            ..
+        } = module
+        else {
+            return Err(format!("{} isn't a Lookup module", module.name()));
+        };
+
+        let path = |x: &str| x.parse().map_err(|e| format!("invalid path {x}: {e}"));
+        if join.is_empty() {
+            return Err("join is empty".to_owned());
+        }
+        let join = join
+            .iter()
+            .map(|x| Ok((path(&x.input)?, path(&x.table)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let lookup = lookup
+            .iter()
+            .map(|x| path(x))
+            .collect::<Result<Vec<DataPath>, String>>()?;
+        let Some(file) = table_file else {
+            return Err(format!(
+                "table {table} isn't in the lookup_tables of the config"
+            ));
+        };
+        let records = read_table(file).map_err(|e| format!("reading table {table}: {e}"))?;
+
+        let mut index: HashMap<_, Vec<_>> = HashMap::new();
+        let mut unjoinable = 0;
+        for (n, record) in records.iter().enumerate() {
+            let key = join
+                .iter()
+                .map(|(_, x)| datapath::lookup(record, Root::Payload, &x.segments).cloned())
+                .collect::<Result<Vec<_>, _>>();
+            match key {
+                Ok(key) => index
+                    .entry(Value::from(key).to_string())
+                    .or_default()
+                    .push(n),
+                Err(_) => unjoinable += 1,
+            }
+        }
+        if unjoinable != 0 {
+            tracing::warn!("{unjoinable} records of table {table} don't have every join path");
+        }
+
+        Ok(Self {
+            join,
+            lookup,
+            unique: *expect_unique_match,
+            records,
+            index,
+        })
+    }
+
+    pub fn apply(&self, msg: &mut Message) -> Result<(), String> {
+        let key = self
+            .join
+            .iter()
+            .map(|(x, _)| x.get(msg).map_err(|e| format!("no join value: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = Value::from(key).to_string();
+        let record = match self.index.get(&key).map(Vec::as_slice) {
+            None | Some([]) => return Err(format!("no record matches {key}")),
+            Some([n, ..]) if !self.unique => *n,
+            Some([n]) => *n,
+            Some(matches) => {
+                return Err(format!("{} records match {key}", matches.len()));
+            }
+        };
+        for path in &self.lookup {
+            let value = datapath::lookup(&self.records[record], Root::Payload, &path.segments)
+                .map_err(|e| format!("record {} of the table: {e}", record + 1))?;
+            path.set(msg, value.clone())
+                .map_err(|e| format!("can't write {path}: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn read_table(path: &Path) -> Result<Vec<Value>, String> {
+    let mut records = vec![];
+    let mut error = None;
+    crate::output::for_each_record(path, |record| match serde_json::from_str(&record) {
+        Ok(record) => {
+            records.push(record);
+            true
+        }
+        Err(e) => {
+            error = Some(format!("record {}: {e}", records.len() + 1));
+            false
+        }
+    })
+    .map_err(|e| e.to_string())?;
+    match error {
+        Some(e) => Err(e),
+        None => Ok(records),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opts::MessageInner;
+
+    #[test]
+    fn records_are_joined() {
+        let dir = std::env::temp_dir().join(format!("lookup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("countries.jsonl");
+        let table = [
+            r#"{"countryName": "France", "countryCode": "FR"}"#,
+            r#"{"countryName": "Peru", "countryCode": "PE"}"#,
+            r#"{"countryName": "Peru", "countryCode": "PU"}"#,
+        ];
+        std::fs::write(&file, table.join("\n")).unwrap();
+
+        let mut module: Module = serde_json::from_value(serde_json::json!({
+            "type": "Lookup",
+            "name": "lookup",
+            "table": "countriesTable",
+            "expectUniqueMatch": true,
+            "join": [{"input": "country", "table": "countryName"}],
+            "lookup": ["countryCode"],
+        }))
+        .unwrap();
+        if let Module::Lookup { table_file, .. } = &mut module {
+            *table_file = Some(file);
+        }
+        let lookup = Lookup::new(&module).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let apply = |payload: &str| {
+            let mut msg = Message {
+                inner: MessageInner::from(payload.to_owned()),
+                date: Default::default(),
+                source: None,
+            };
+            lookup
+                .apply(&mut msg)
+                .map(|()| msg.to_json()["countryCode"].clone())
+        };
+        assert_eq!(apply(r#"{"country": "France"}"#), Ok("FR".into()));
+        assert_eq!(
+            apply(r#"{"country": "Spain"}"#),
+            Err(r#"no record matches ["Spain"]"#.to_owned())
+        );
+        assert_eq!(
+            apply(r#"{"country": "Peru"}"#),
+            Err(r#"2 records match ["Peru"]"#.to_owned())
+        );
+        assert!(apply(r#"{"pays": "France"}"#)
+            .unwrap_err()
+            .starts_with("no join value"));
+    }
+}
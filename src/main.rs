@@ -1,11 +1,22 @@
-use emulator_rs::{driver, opts::Options};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use emulator_rs::{
+    cache::Cache,
+    datetime::TimeZone,
+    diff, driver, ingestion,
+    opts::{Encoding, Input, Options, OutputCompression, OutputFormat},
+    playbook::Playbook,
+    tree::PbTree,
+};
+use eyre::Context;
 use std::{
-    env::args,
     fs::{read_to_string, File},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime},
 };
-use tracing::{debug, info, trace};
+use tracing::{debug, error, info, trace};
 use tracing_subscriber::{
-    filter, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
+    filter::LevelFilter, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
 };
 
 #[cfg(release)]
@@ -14,43 +25,284 @@ use jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+#[derive(Debug, Parser)]
+#[command(version, about = "Runs BMP playbooks locally")]
+struct Cli {
+    /// More logs, can be repeated
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Less logs, can be repeated
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    quiet: u8,
+    /// Also write every log, down to TRACE, to this file
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run a playbook as described by a config file
+    Run {
+        config: PathBuf,
+        #[command(flatten)]
+        overrides: Overrides,
+    },
+    /// Run a config, and again every time the config, the playbook, its rules or the inputs
+    /// change
+    Watch {
+        config: PathBuf,
+        #[command(flatten)]
+        overrides: Overrides,
+    },
+    /// Manage the cache of the anonymization tool output
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Check a playbook for errors without running it
+    Validate { playbook: PathBuf },
+    /// Print the module tree of a playbook
+    Tree { playbook: PathBuf },
+    /// Compare the outputs of two runs, either two files or two output directories
+    Diff { a: PathBuf, b: PathBuf },
+    /// Run again with the config saved in an output directory by a previous run
+    Replay {
+        #[arg(default_value = "bmp_emulator")]
+        dir: PathBuf,
+        #[command(flatten)]
+        overrides: Overrides,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheCommand {
+    /// Remove every cache entry
+    Clear {
+        #[arg(long, default_value = ".cache")]
+        cache_dir: PathBuf,
+    },
+}
+
+/// Overrides for the fields of the config file
+#[derive(Debug, Clone, Args)]
+struct Overrides {
+    /// Replaces the inputs of the config, can be repeated
+    #[arg(short, long)]
+    input: Vec<String>,
+    #[arg(short, long)]
+    output_dir: Option<PathBuf>,
+    #[arg(short, long)]
+    format: Option<OutputFormat>,
+    #[arg(long)]
+    compression: Option<OutputCompression>,
+    /// Process date of every input, over the ones of the config and of the file names
+    #[arg(long)]
+    process_date: Option<String>,
+    /// Zone of the dates without an offset, like UTC, +01:00 or Europe/Paris
+    #[arg(long)]
+    time_zone: Option<TimeZone>,
+    /// Encoding of every input
+    #[arg(long)]
+    encoding: Option<Encoding>,
+    /// Skip this many lines at the start of every input
+    #[arg(long)]
+    head: Option<u32>,
+    /// Skip this many lines at the end of every input
+    #[arg(long)]
+    tail: Option<u32>,
+    /// Exclude a module, can be repeated
+    #[arg(short = 'x', long = "exclude")]
+    excluded_modules: Vec<String>,
+    #[arg(long)]
+    start_at: Option<String>,
+    #[arg(long)]
+    stop_after: Option<String>,
+    /// Don't read nor write the cache of the anonymization tool output
+    #[arg(long)]
+    no_cache: bool,
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+}
+
+impl Overrides {
+    fn apply(self, opts: &mut Options) {
+        if !self.input.is_empty() {
+            opts.input = self
+                .input
+                .into_iter()
+                .map(|path| Input {
+                    path,
+                    metadata: None,
+                    date_pattern: None,
+                    encoding: Default::default(),
+                })
+                .collect();
+        }
+        if let Some(encoding) = self.encoding {
+            opts.input.iter_mut().for_each(|x| x.encoding = encoding);
+        }
+        opts.excluded_modules.extend(self.excluded_modules);
+        let ing = &mut opts.ingestion_opts;
+        ing.head = self.head.unwrap_or(ing.head);
+        ing.tail = self.tail.unwrap_or(ing.tail);
+        ing.cache.enabled &= !self.no_cache;
+        ing.cache.dir = self.cache_dir.unwrap_or(ing.cache.dir.clone());
+        opts.output_dir = self.output_dir.unwrap_or(opts.output_dir.clone());
+        opts.output_format = self.format.unwrap_or(opts.output_format);
+        opts.output_compression = self.compression.unwrap_or(opts.output_compression);
+        opts.process_date = self.process_date.or(opts.process_date.take());
+        opts.time_zone = self.time_zone.unwrap_or(opts.time_zone.clone());
+        opts.start_at = self.start_at.or(opts.start_at.take());
+        opts.stop_after = self.stop_after.or(opts.stop_after.take());
+    }
+}
+
 // https://stackoverflow.com/a/70042590
-fn tracing() {
+fn tracing(level: LevelFilter, log_file: Option<&Path>) -> eyre::Result<()> {
     let stdout_log = tracing_subscriber::fmt::layer().pretty();
 
     // A layer that logs events to a file.
-    let file = File::create("debug.log");
-    let file = match file {
-        Ok(file) => file,
-        Err(error) => panic!("Error: {:?}", error),
-    };
-    let debug_log = tracing_subscriber::fmt::layer().with_writer(std::sync::Arc::new(file));
+    let debug_log = log_file
+        .map(|path| {
+            let file = File::create(path).wrap_err_with(|| format!("creating {path:?}"))?;
+            eyre::Ok(tracing_subscriber::fmt::layer().with_writer(std::sync::Arc::new(file)))
+        })
+        .transpose()?;
 
     tracing_subscriber::registry()
-        .with(
-            stdout_log
-                // Add an `INFO` filter to the stdout logging layer
-                .with_filter(filter::LevelFilter::TRACE)
-                // Combine the filtered `stdout_log` layer with the
-                // `debug_log` layer, producing a new `Layered` layer.
-                .and_then(debug_log),
-        )
+        .with(stdout_log.with_filter(level))
+        .with(debug_log)
         .init();
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() {
-    tracing();
-    let path = args().nth(1).expect("usage: emulator-rs [config]");
-    trace!("reading config file from {path}");
-    let opts = read_to_string(path).unwrap();
-
+fn read_options(path: &Path) -> eyre::Result<Options> {
+    trace!("reading config file from {path:?}");
+    let opts = read_to_string(path).wrap_err_with(|| format!("reading {path:?}"))?;
     let mut de = serde_json::Deserializer::from_str(&opts);
-    let opts: Options = serde_path_to_error::deserialize(&mut de).unwrap();
-    std::fs::create_dir_all("bmp_emulator").unwrap();
+    serde_path_to_error::deserialize(&mut de).wrap_err_with(|| format!("parsing {path:?}"))
+}
+
+async fn run(mut opts: Options, overrides: Overrides) -> eyre::Result<ExitCode> {
+    overrides.apply(&mut opts);
     info!("Starting emulator");
     debug!("{:?}", opts);
-    driver::entry(opts).await;
+    driver::entry(opts).await?;
 
     info!("Emulator finished successfully");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn watched_files(config: &Path, opts: &Options) -> Vec<PathBuf> {
+    let mut ret = vec![config.to_owned(), opts.playbook_file_path.clone()];
+    ret.extend(opts.input.iter().map(|x| PathBuf::from(&x.path)));
+    // Directories are watched too, they change when a file is added
+    if let Ok(inputs) = ingestion::expand_inputs(opts.input.clone()) {
+        ret.extend(inputs.into_iter().map(|x| PathBuf::from(x.path)));
+    }
+    if let Ok(pb) = Playbook::try_new(&opts.playbook_file_path) {
+        for dir in ["logic", "../../libraries"] {
+            if let Ok(dir) = std::fs::read_dir(pb.channel_root_path.join(dir)) {
+                ret.extend(dir.filter_map(Result::ok).map(|x| x.path()));
+            }
+        }
+    }
+    ret
+}
+
+fn mtimes(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|x| std::fs::metadata(x).and_then(|x| x.modified()).ok())
+        .collect()
+}
+
+async fn watch(config: PathBuf, overrides: Overrides) -> eyre::Result<ExitCode> {
+    loop {
+        // Taken before running so that changes made during the run aren't missed
+        let mut files = vec![config.clone()];
+        let before;
+        match read_options(&config) {
+            Ok(mut opts) => {
+                overrides.clone().apply(&mut opts);
+                files = watched_files(&config, &opts);
+                before = mtimes(&files);
+                // In its own task so that a panic doesn't end the watch
+                match tokio::spawn(driver::entry(opts)).await {
+                    Ok(Ok(())) => info!("run finished, waiting for changes"),
+                    Ok(Err(e)) => error!("run failed: {e:?}"),
+                    Err(e) => error!("run panicked: {e}"),
+                }
+            }
+            Err(e) => {
+                before = mtimes(&files);
+                error!("{e:?}");
+            }
+        }
+
+        while mtimes(&files) == before {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        info!("change detected, running again");
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<ExitCode> {
+    let cli = Cli::parse();
+    let levels = [
+        LevelFilter::ERROR,
+        LevelFilter::WARN,
+        LevelFilter::INFO,
+        LevelFilter::DEBUG,
+        LevelFilter::TRACE,
+    ];
+    let level = (2 + cli.verbose as usize).saturating_sub(cli.quiet as usize);
+    tracing(levels[level.min(4)], cli.log_file.as_deref())?;
+
+    match cli.command {
+        Command::Run { config, overrides } => run(read_options(&config)?, overrides).await,
+        Command::Watch { config, overrides } => watch(config, overrides).await,
+        Command::Replay { dir, overrides } => {
+            let opts = read_options(&dir.join("_config.json"))?;
+            run(opts, overrides).await
+        }
+        Command::Cache {
+            command: CacheCommand::Clear { cache_dir },
+        } => {
+            let freed = Cache::clear(&cache_dir)?;
+            println!("freed {:.1} MB", freed as f64 / (1024. * 1024.));
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Validate { playbook } => {
+            let problems = Playbook::try_new(playbook)?.validate();
+            for problem in &problems {
+                println!("{problem}");
+            }
+            if problems.is_empty() {
+                println!("playbook is valid");
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::FAILURE)
+            }
+        }
+        Command::Tree { playbook } => {
+            let pb = Playbook::try_new(playbook)?;
+            print!("{}", PbTree::new(&pb.pb.modules));
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Diff { a, b } => {
+            let diffs = diff::diff_paths(&a, &b)?;
+            for diff in &diffs {
+                println!("{diff}");
+            }
+            if diffs.iter().all(diff::FileDiff::is_same) {
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::FAILURE)
+            }
+        }
+    }
 }
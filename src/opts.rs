@@ -1,66 +1,140 @@
-use crate::{playbook::Playbook, tree::PbTree};
+use crate::{
+    datapath::{self, Segment},
+    datetime::{DateTime, TimeZone},
+    playbook::{EventTimeField, Playbook},
+    tree::PbTree,
+};
 use ijson::{IObject, IString, IValue};
 use serde::{Deserialize, Serialize};
-use std::{env, fmt::Display, path::PathBuf, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::{Debug, Display, Write as _},
+    ops::Deref,
+    path::PathBuf,
+    sync::Arc,
+};
 
 use itertools::Itertools;
 
-pub type Payload2 = Arc<str>;
-// Arc -> ArcInner -> str
-pub type Payload = Arc<String>; // PERF: Experiment with Arc<String>
 pub type JsonObj = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Clone, Deserialize)]
+#[serde(from = "String")]
+pub struct Payload(Arc<str>);
+
+impl Payload {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn get(&self, path: &[Segment]) -> Option<serde_json::Value> {
+        let span = datapath::span(&self.0, path).ok()??;
+        serde_json::from_str(&self.0[span]).ok()
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        Arc::ptr_eq(&a.0, &b.0)
+    }
+}
+
+impl From<String> for Payload {
+    fn from(raw: String) -> Self {
+        Self(raw.into())
+    }
+}
+
+impl Deref for Payload {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Payload").field(&&*self.0).finish()
+    }
+}
+
+/// A message as modules see it. `payload` never has a `billingmediation` key, it's only joined
+/// with `billingmediation` when the message is serialized.
 #[derive(Debug, Clone, Deserialize)]
 pub struct MessageInner {
-    pub billingmediation: serde_json::Map<String, serde_json::Value>,
-    // TODO: pick a more compact repr / compress inputs?
+    pub billingmediation: JsonObj,
     pub payload: Payload,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct MessageInnerTemp {
-    #[serde(default)]
-    pub billingmediation: JsonObj,
-    #[serde(flatten)]
-    pub payload: JsonObj,
+impl MessageInner {
+    pub fn from_with_bm(value: &str) -> Result<Self, String> {
+        Ok(Self::from_with_source(value)?.0)
+    }
+
+    pub fn from_with_source(value: &str) -> Result<(Self, Reserved), String> {
+        let members = datapath::members(value)?.ok_or("a message must be a json object")?;
+
+        let mut billingmediation = JsonObj::new();
+        let mut reserved = Reserved::default();
+        let mut kept = vec![];
+        for (key, member) in &members {
+            if datapath::key_eq(&value[key.clone()], "billingmediation") {
+                billingmediation = serde_json::from_str(&value[member.clone()])
+                    .map_err(|e| format!("billingmediation must be an object: {e}"))?;
+            } else if datapath::key_eq(&value[key.clone()], "_source") {
+                reserved.source = serde_json::from_str(&value[member.clone()]).ok();
+            } else if datapath::key_eq(&value[key.clone()], "_stream") {
+                reserved.stream = serde_json::from_str(&value[member.clone()]).ok();
+            } else {
+                kept.push(&value[key.start..member.end]);
+            }
+        }
+        let payload = if kept.len() == members.len() {
+            value.trim().to_owned()
+        } else {
+            format!("{{{}}}", kept.iter().format(","))
+        };
+        let inner = Self {
+            billingmediation,
+            payload: payload.into(),
+        };
+        Ok((inner, reserved))
+    }
 }
 
-impl MessageInner {
-    // PERF: This could be optimized a lot by removing the "billingmediation" key and replacing it
-    // with spaces and just constructing the bm object later on. In fact, the bm could be lazily
-    // constructed? In fact whether something is needed could be analyzed upfront.
-    pub fn from_with_bm(value: &str) -> Self {
-        let tmp: MessageInnerTemp = serde_json::from_str(value).unwrap();
-        let payload = serde_json::to_string(&tmp.payload).unwrap();
-        Self {
-            billingmediation: tmp.billingmediation,
-            payload: Arc::from(payload),
+#[derive(Debug, Default)]
+pub struct Reserved {
+    pub source: Option<Source>,
+    pub stream: Option<String>,
+}
+
+fn write_with(
+    f: &mut std::fmt::Formatter<'_>,
+    payload: &str,
+    extra: &[(&str, String)],
+) -> std::fmt::Result {
+    let body = payload.trim();
+    let body = body
+        .strip_prefix('{')
+        .and_then(|x| x.strip_suffix('}'))
+        .expect("payloads are json objects");
+    f.write_char('{')?;
+    f.write_str(body)?;
+    let mut empty = body.trim().is_empty();
+    for (key, value) in extra {
+        if !empty {
+            f.write_char(',')?;
         }
+        empty = false;
+        write!(f, "\"{key}\":{value}")?;
     }
+    f.write_char('}')
 }
 
 impl Display for MessageInner {
-    // fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    //     let key = r#","billingmediation":"#;
-    //     let bm: String = serde_json::to_string(&self.billingmediation).unwrap();
-    //     let mut ret = String::with_capacity(bm.len() + self.payload.len() + key.len());
-    //     assert_ne!(bm.len(), 0);
-    //     ret.push_str(&self.payload[..self.payload.len() - 1]);
-    //     ret.push_str(key);
-    //     ret.push_str(&bm);
-    //     ret.push('}');
-    //     f.write_str(&ret)?;
-    //     Ok(())
-    // }
-    // PERF: This is not great :P (but it doesn't use memory for too long)
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut base: JsonObj = serde_json::from_str(&self.payload).unwrap();
-        base.insert(
-            "billingmediation".into(),
-            self.billingmediation.clone().into(),
-        );
-        let bm: String = serde_json::to_string(&base).unwrap();
-        f.write_str(&bm)?;
-        Ok(())
+        let bm = serde_json::to_string(&self.billingmediation).unwrap();
+        write_with(f, &self.payload, &[("billingmediation", bm)])
     }
 }
 
@@ -77,6 +151,60 @@ impl<T: Into<Payload>> From<T> for MessageInner {
 pub struct Message {
     pub inner: MessageInner,
     pub date: IString, // TODO Arc/intern this
+    pub source: Option<Source>,
+}
+
+/// Where a message was ingested from. Every message derived from it keeps it, and it's written
+/// under the reserved `_source` key of the outputs.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Source {
+    pub file: IString,
+    pub line: usize,
+    pub index: usize,
+}
+
+impl Message {
+    pub fn to_json(&self) -> JsonObj {
+        serde_json::from_str(&self.to_string()).unwrap()
+    }
+
+    pub fn on_stream<'a>(&'a self, stream: &'a str) -> impl Display + 'a {
+        OnStream(self, stream)
+    }
+
+    fn write(&self, f: &mut std::fmt::Formatter<'_>, stream: Option<&str>) -> std::fmt::Result {
+        let mut extra = vec![(
+            "billingmediation",
+            serde_json::to_string(&self.inner.billingmediation).unwrap(),
+        )];
+        if let Some(source) = &self.source {
+            extra.push(("_source", serde_json::to_string(source).unwrap()));
+        }
+        if let Some(stream) = stream {
+            extra.push(("_stream", serde_json::to_string(stream).unwrap()));
+        }
+        write_with(f, &self.inner.payload, &extra)
+    }
+
+    /// The process date of the message, `None` if it has none. The driver checks process dates
+    /// and writes them in RFC 3339 before anything is ingested.
+    pub fn process_date(&self) -> Option<DateTime> {
+        DateTime::parse_iso(&self.date, &TimeZone::UTC).ok()
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, None)
+    }
+}
+
+struct OnStream<'a>(&'a Message, &'a str);
+
+impl Display for OnStream<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.write(f, Some(self.1))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
@@ -88,6 +216,73 @@ pub struct InputMetadata {
 pub struct Input {
     pub path: String,
     pub metadata: Option<InputMetadata>,
+    /// Regex matched against the file names to get their process date, with the named groups
+    /// `y`, `m`, `d` and optionally `H`, `M` and `S`, in the `time_zone` of the config. `y` has 2
+    /// or 4 digits, 2 digit years are in the 2000s: `24` is 2024. Files that don't match fall back
+    /// to `metadata`. For the PPT.EOT names (`D240401.T000922`):
+    ///
+    /// `D(?<y>\d{2})(?<m>\d{2})(?<d>\d{2})\.T(?<H>\d{2})(?<M>\d{2})(?<S>\d{2})`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_pattern: Option<String>,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default, clap::ValueEnum,
+)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    #[value(name = "utf-8")]
+    Utf8,
+    #[serde(rename = "iso-8859-1", alias = "latin1")]
+    #[value(name = "iso-8859-1", alias = "latin1")]
+    Latin1,
+    #[serde(rename = "cp037")]
+    #[value(name = "cp037")]
+    Cp037,
+    #[serde(rename = "cp500")]
+    #[value(name = "cp500")]
+    Cp500,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+#[serde(default)]
+pub struct AnonymizerOpts {
+    pub java: PathBuf,
+    pub jar: PathBuf,
+    pub command: String,
+    pub jvm_opts: Vec<String>,
+}
+
+impl Default for AnonymizerOpts {
+    fn default() -> Self {
+        Self {
+            java: "java".into(),
+            jar: "deps/anonymization.jar".into(),
+            command: "edidumpjson".to_owned(),
+            jvm_opts: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+#[serde(default)]
+pub struct CacheOpts {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub max_size_mb: u64,
+}
+
+impl Default for CacheOpts {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: ".cache".into(),
+            max_size_mb: 2048,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, Default)]
@@ -96,9 +291,34 @@ pub struct IngestionOpts {
     pub head: u32,
     #[serde(default)]
     pub tail: u32,
+    /// Lines of an input anonymized together, every input is a single batch when 0. Batches reach
+    /// the pipeline as soon as they are ready. They're cut where a cache chunk ends, so they can
+    /// be a bit longer.
     #[serde(default)]
     pub batch_size: u32,
     pub regex: Option<String>,
+    #[serde(default)]
+    pub anonymizer: AnonymizerOpts,
+    #[serde(default)]
+    pub cache: CacheOpts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Jsonl,
+    Json,
+    Streams,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -106,12 +326,54 @@ pub struct Options {
     pub playbook_file_path: PathBuf,
     pub input: Vec<Input>,
     pub reports_dir: Option<String>,
+    #[serde(default = "default_output_dir")]
+    pub output_dir: PathBuf,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    #[serde(default)]
+    pub output_compression: OutputCompression,
     #[serde(default)]
     pub excluded_modules: Vec<String>,
+    #[serde(default)]
+    pub stop_at_excluded: bool,
     pub process_date: Option<String>,
+    #[serde(default)]
+    pub time_zone: TimeZone,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period: Option<RunPeriod>,
+    pub start_at: Option<String>,
+    pub start_input: Option<PathBuf>,
+    pub stop_after: Option<String>,
     pub json_lookup_table: Option<(String, String)>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub lookup_tables: BTreeMap<String, PathBuf>,
     #[serde(default)]
     pub show_bench: bool,
     #[serde(default)]
     pub ingestion_opts: IngestionOpts,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub module_opts: BTreeMap<String, ModuleOpts>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModuleOpts {
+    /// Splitting: record `billingmediation.split = {index, count, parentId}` on every message,
+    /// `index` is null and `count` 0 for the ones that had nothing to split
+    pub record_split: bool,
+    pub event_time: Option<EventTimeField>,
+    pub allowed_lateness: Option<String>,
+    pub late_stream: Option<String>,
+}
+
+/// Dates or date-times, like `process_date`. The end is excluded: 2024-04-01 to 2024-05-01 is
+/// April.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunPeriod {
+    pub start: String,
+    pub end: String,
+}
+
+fn default_output_dir() -> PathBuf {
+    PathBuf::from("bmp_emulator")
 }
@@ -0,0 +1,295 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder};
+use itertools::Itertools;
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserializer as _, Serialize,
+};
+
+use crate::{
+    opts::{Message, OutputCompression, OutputFormat},
+    playbook::Module,
+    reporting::Clock,
+    summary::ModuleMetrics,
+};
+
+#[derive(Debug, Clone)]
+pub struct OutputOpts {
+    pub dir: PathBuf,
+    pub format: OutputFormat,
+    pub compression: OutputCompression,
+    pub reports_dir: PathBuf,
+    pub clock: Clock,
+}
+
+impl OutputOpts {
+    pub fn path(&self, module: &str, route: &str) -> PathBuf {
+        let mut name = match self.format {
+            OutputFormat::Jsonl => module.to_owned(),
+            OutputFormat::Json => format!("{module}.json"),
+            OutputFormat::Streams => format!("{module}.{route}.jsonl"),
+        };
+        match self.compression {
+            OutputCompression::None => {}
+            OutputCompression::Gzip => name.push_str(".gz"),
+            OutputCompression::Zstd => name.push_str(".zst"),
+        }
+        self.dir.join(name)
+    }
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(buf),
+            Sink::Gzip(w) => w.write(buf),
+            Sink::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            Sink::Gzip(w) => w.flush(),
+            Sink::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+pub struct OutputFile {
+    sink: Sink,
+    format: OutputFormat,
+    count: usize,
+}
+
+impl OutputFile {
+    pub fn create(path: &Path, opts: &OutputOpts) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let sink = match opts.compression {
+            OutputCompression::None => Sink::Plain(file),
+            OutputCompression::Gzip => Sink::Gzip(GzEncoder::new(file, Default::default())),
+            OutputCompression::Zstd => Sink::Zstd(zstd::Encoder::new(file, 0)?),
+        };
+        Ok(Self {
+            sink,
+            format: opts.format,
+            count: 0,
+        })
+    }
+
+    /// Writes `msg`, which is on `stream`. Unless the file is the one of `stream`, the message
+    /// gets a `_stream` key.
+    pub fn write(&mut self, msg: &Message, stream: &str) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Jsonl => {
+                if self.count != 0 {
+                    self.sink.write_all(b"\n")?;
+                }
+                write!(self.sink, "{}", msg.on_stream(stream))?;
+            }
+            OutputFormat::Streams => {
+                if self.count != 0 {
+                    self.sink.write_all(b"\n")?;
+                }
+                write!(self.sink, "{msg}")?;
+            }
+            OutputFormat::Json => {
+                self.sink
+                    .write_all(if self.count == 0 { b"[\n" } else { b",\n" })?;
+                let json: serde_json::Value =
+                    serde_json::from_str(&msg.on_stream(stream).to_string())?;
+                serde_json::to_writer_pretty(&mut self.sink, &json)?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.format == OutputFormat::Json {
+            self.sink
+                .write_all(if self.count == 0 { b"[]" } else { b"\n]" })?;
+        }
+        match self.sink {
+            Sink::Plain(mut w) => w.flush(),
+            Sink::Gzip(w) => w.finish()?.flush(),
+            Sink::Zstd(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexEntry {
+    pub count: u64,
+    pub file: PathBuf,
+    pub interleaved: bool,
+}
+
+pub fn write_index(
+    output: &OutputOpts,
+    modules: &[Module],
+    metrics: &[ModuleMetrics],
+) -> io::Result<()> {
+    let index: BTreeMap<&str, BTreeMap<&str, IndexEntry>> = metrics
+        .iter()
+        .filter_map(|m| Some((m, modules.iter().find(|x| x.name() == m.name)?)))
+        .map(|(m, md)| {
+            let streams = md
+                .streams()
+                .chain(m.streams.keys().map(String::as_str))
+                .unique()
+                .map(|stream| {
+                    let entry = IndexEntry {
+                        count: m.streams.get(stream).copied().unwrap_or(0),
+                        file: output
+                            .path(&m.name, stream)
+                            .strip_prefix(&output.dir)
+                            .unwrap()
+                            .into(),
+                        interleaved: output.format != OutputFormat::Streams,
+                    };
+                    (stream, entry)
+                })
+                .collect();
+            (md.name(), streams)
+        })
+        .collect();
+    let json = serde_json::to_string_pretty(&index).expect("index is always valid json");
+    std::fs::write(output.dir.join("_index.json"), json)
+}
+
+fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    Ok(match path.extension().and_then(|x| x.to_str()) {
+        Some("gz") => Box::new(BufReader::new(GzDecoder::new(file))),
+        Some("zst") => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+        _ => Box::new(BufReader::new(file)),
+    })
+}
+
+pub fn read_output(path: &Path) -> io::Result<String> {
+    let mut ret = String::new();
+    open(path)?.read_to_string(&mut ret)?;
+    Ok(ret)
+}
+
+/// Calls `f` with the json text of every message of a file written by [`OutputFile`], until it
+/// returns false. Json arrays are read one element at a time, the file is never loaded whole.
+pub fn for_each_record(path: &Path, mut f: impl FnMut(String) -> bool) -> io::Result<()> {
+    let mut reader = open(path)?;
+    let is_array = loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|x| !x.is_ascii_whitespace()) {
+            Some(n) => {
+                let is_array = buf[n] == b'[';
+                reader.consume(n);
+                break is_array;
+            }
+            None => {
+                let n = buf.len();
+                reader.consume(n);
+            }
+        }
+    };
+    if !is_array {
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() && !f(line) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    struct Elements<'a, F> {
+        f: &'a mut F,
+        stopped: &'a mut bool,
+    }
+
+    impl<'de, F: FnMut(String) -> bool> Visitor<'de> for Elements<'_, F> {
+        type Value = ();
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("an array of messages")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+            while let Some(x) = seq.next_element::<serde_json::Value>()? {
+                if !(self.f)(x.to_string()) {
+                    *self.stopped = true;
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut stopped = false;
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let res = de.deserialize_seq(Elements {
+        f: &mut f,
+        stopped: &mut stopped,
+    });
+    match res {
+        // What's left after stopping isn't read
+        Err(_) if stopped => Ok(()),
+        res => res
+            .and_then(|()| de.end())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+pub fn read_records(path: &Path) -> io::Result<Vec<String>> {
+    let mut ret = vec![];
+    for_each_record(path, |x| {
+        ret.push(x);
+        true
+    })?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_are_streamed() {
+        let dir = std::env::temp_dir().join(format!("records-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (array, lines) = (dir.join("a.json"), dir.join("a"));
+        std::fs::write(&array, "[\n{\"a\": [1]},\n {\"b\": 2}, 3]").unwrap();
+        std::fs::write(&lines, "{\"a\":[1]}\n\n{\"b\":2}\n3").unwrap();
+
+        for path in [&array, &lines] {
+            assert_eq!(
+                read_records(path).unwrap(),
+                [r#"{"a":[1]}"#, r#"{"b":2}"#, "3"]
+            );
+            let mut first = vec![];
+            for_each_record(path, |x| {
+                first.push(x);
+                false
+            })
+            .unwrap();
+            assert_eq!(first, [r#"{"a":[1]}"#]);
+        }
+        std::fs::write(&array, "[{}, {]").unwrap();
+        assert!(read_records(&array).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,19 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::{self},
     path::{Path, PathBuf},
     thread::available_parallelism,
 };
 
+use eyre::{Context, ContextCompat};
 use futures::future::join_all;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use tokio::fs::read_to_string;
 use tracing::{instrument, trace};
 
+use crate::opts::ModuleOpts;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlaybookData {
     #[serde(rename = "bmpVersion")]
@@ -26,19 +29,170 @@ pub struct Playbook {
 
 impl Playbook {
     pub fn new<I: AsRef<Path>>(path: I) -> Self {
+        Self::try_new(path).unwrap()
+    }
+
+    pub fn try_new<I: AsRef<Path>>(path: I) -> eyre::Result<Self> {
         let path = &path.as_ref();
-        let root = path.parent().unwrap().parent().unwrap().to_owned();
+        // Playbooks live in <channel>/playbook/
+        let root = path
+            .parent()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new("."))
+            .to_owned();
 
         trace!("reading playbook from {path:?}");
-        let pb = fs::read_to_string(path).unwrap();
-        let pb = serde_yaml::from_str(&pb).unwrap(); // PERF: From reader
-        Self {
+        let pb = fs::read_to_string(path).wrap_err_with(|| format!("reading {path:?}"))?;
+        let de = serde_yaml::Deserializer::from_str(&pb); // PERF: From reader
+        let pb =
+            serde_path_to_error::deserialize(de).wrap_err_with(|| format!("parsing {path:?}"))?;
+        Ok(Self {
             pb,
             channel_root_path: root,
+        })
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let modules = &self.pb.modules;
+
+        match modules.first() {
+            Some(Module::MessageIngestion { .. } | Module::FileIngestion { .. }) => {}
+            Some(md) => problems.push(format!(
+                "the first module must be an ingestion module, {} is {}",
+                md.name(),
+                md.kind()
+            )),
+            None => problems.push("the playbook has no modules".to_owned()),
+        }
+
+        for (n, md) in modules.iter().enumerate() {
+            let name = md.name();
+            if modules[..n].iter().any(|x| x.name() == name) {
+                problems.push(format!("module name {name} is used more than once"));
+            }
+
+            let Some(input) = md.input() else { continue };
+            let Some((parent, stream)) = input.split_once('.') else {
+                problems.push(format!("{name}: input {input} should be <module>.<stream>"));
+                continue;
+            };
+            match modules[..n].iter().find(|x| x.name() == parent) {
+                None => problems.push(format!(
+                    "{name}: input module {parent} doesn't exist or comes after it"
+                )),
+                Some(parent) if !parent.streams().any(|x| x == stream) => problems.push(format!(
+                    "{name}: {} has no stream {stream}, it has {}",
+                    parent.name(),
+                    parent.streams().join(", ")
+                )),
+                Some(_) => {}
+            }
+        }
+
+        let sources = ["logic", "../../libraries"]
+            .into_iter()
+            .filter_map(|dir| std::fs::read_dir(self.channel_root_path.join(dir)).ok())
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|x| x.file_name())
+            .collect_vec();
+        for md in modules {
+            if let Module::Splitting {
+                array_path, name, ..
+            } = md
+            {
+                if let Err(e) = array_path.parse::<crate::datapath::DataPath>() {
+                    problems.push(format!("{name}: invalid arrayPath {array_path}: {e}"));
+                }
+            }
+            if let Module::MessageIngestion {
+                event_time: Some(filter),
+                name,
+                ..
+            }
+            | Module::FileIngestion {
+                event_time: Some(filter),
+                name,
+                ..
+            } = md
+            {
+                let zone = crate::datetime::TimeZone::UTC;
+                if let Err(e) = crate::ingestion::EventTime::new(filter, zone) {
+                    problems.push(format!("{name}: invalid eventTime: {e}"));
+                }
+            }
+            if let Module::Reporting {
+                scheduling,
+                format,
+                fields,
+                subscriber,
+                name,
+                ..
+            } = md
+            {
+                if format != "CSV" {
+                    problems.push(format!("{name}: unsupported report format {format}"));
+                }
+                let clock = Default::default();
+                let dir = Path::new("");
+                let reporter = crate::reporting::Reporter::new(
+                    name,
+                    scheduling,
+                    fields,
+                    subscriber.as_deref(),
+                    clock,
+                    dir,
+                );
+                if let Err(e) = reporter {
+                    problems.push(format!("{name}: {e}"));
+                }
+            }
+            if let Module::MessageIngestion { select, name, .. }
+            | Module::FileIngestion { select, name, .. } = md
+            {
+                if let Err(e) = crate::ingestion::Selection::new(select) {
+                    problems.push(format!("{name}: invalid select: {e}"));
+                }
+            }
+            if let Module::Lookup {
+                join, lookup, name, ..
+            } = md
+            {
+                let paths = join.iter().flat_map(|x| [&x.input, &x.table]).chain(lookup);
+                for path in paths {
+                    if let Err(e) = path.parse::<crate::datapath::DataPath>() {
+                        problems.push(format!("{name}: invalid path {path}: {e}"));
+                    }
+                }
+            }
+            if let Module::Aggregation { name, .. } = md {
+                if let Err(e) =
+                    crate::aggregation::Aggregator::new(md, crate::datetime::TimeZone::UTC)
+                {
+                    problems.push(format!("{name}: {e}"));
+                }
+            }
+            if let Module::Deduplication { key, name, .. } = md {
+                if let Err(e) = crate::datapath::parse_all(key) {
+                    problems.push(format!("{name}: {e} in key"));
+                }
+            }
+            if let Module::Logic { rules, name, .. } = md {
+                if rules.is_empty() {
+                    problems.push(format!("{name}: Logic modules need at least one rule"));
+                }
+                for rule in rules {
+                    if !sources.iter().any(|x| *x == **rule) {
+                        problems.push(format!("{name}: rule {rule} not found"));
+                    }
+                }
+            }
         }
+        problems
     }
 
-    pub async fn get_scripts(&self) -> Vec<String> {
+    pub async fn get_scripts(&self, excluded: &[String]) -> Vec<String> {
         let mut paths: Vec<PathBuf> = vec![];
         let lib_path = self.channel_root_path.join("../../libraries");
 
@@ -51,7 +205,12 @@ impl Playbook {
             .map(Result::unwrap);
 
         let sources = logics.chain(libs).collect_vec();
-        for md in &self.pb.modules {
+        let modules = self
+            .pb
+            .modules
+            .iter()
+            .filter(|md| !excluded.iter().any(|x| x == md.name()));
+        for md in modules {
             if let Module::Logic { rules, .. } = md {
                 assert!(!rules.is_empty());
 
@@ -106,8 +265,53 @@ pub struct SchemaRecords {
     pub records: IngestionSchema,
 }
 
+/// Where the event time of a message is: read at `path` with the date pattern `format`, or the
+/// process date when `path` is empty.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "frequency")]
+pub struct EventTimeField {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+/// `eventTime` of the ingestion modules: only the messages whose event time is between `start`,
+/// included, and `end`, excluded, are ingested. `start` and `end` are dates or date-times, see
+/// [`crate::datetime::DateTime::parse_iso`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventTimeFilter {
+    #[serde(flatten)]
+    pub field: EventTimeField,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldSelection {
+    Add(String),
+    Del(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Join {
+    pub input: String,
+    pub table: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SumSource {
+    Count(u8),
+    Path(String),
+}
+
+fn late_stream() -> String {
+    "late".to_owned()
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum ReportingFrequency {
     #[serde(rename = "daily")]
     Daily,
@@ -117,29 +321,52 @@ pub enum ReportingFrequency {
     Monthly,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scheduling {
+    pub frequency: ReportingFrequency,
+    #[serde(rename = "delayMinutes", default)]
+    pub delay_minutes: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Module {
     MessageIngestion {
         schema: IngestionSchema,
+        #[serde(rename = "eventTime", default, skip_serializing_if = "Option::is_none")]
+        event_time: Option<EventTimeFilter>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        select: Vec<FieldSelection>,
         name: String,
     },
     FileIngestion {
         schema: SchemaRecords,
+        #[serde(rename = "eventTime", default, skip_serializing_if = "Option::is_none")]
+        event_time: Option<EventTimeFilter>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        select: Vec<FieldSelection>,
         name: String,
     },
     Splitting {
         #[serde(rename = "arrayPath")]
         array_path: String,
+        /// Lets the messages with a missing or empty array through once, without the array
         #[serde(rename = "allowEmpty")]
         allow_empty: bool,
+        /// Not a playbook field, set from [`ModuleOpts`]
+        #[serde(skip)]
+        record_split: bool,
         name: String,
         input: Option<String>,
     },
     Reporting {
-        // fields: HashMap<String, String>,
-        scheduling: ReportingFrequency,
+        scheduling: Scheduling,
         format: String,
+        #[serde(default)]
+        fields: Vec<BTreeMap<String, String>>,
+        /// Path of who the report is for, there's one report per subscriber and cycle
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subscriber: Option<String>,
         name: String,
         input: Option<String>,
     },
@@ -153,7 +380,16 @@ pub enum Module {
     },
     Aggregation {
         key: Vec<String>,
-        // sums: HashMap<String, String>,
+        #[serde(default)]
+        sums: BTreeMap<String, SumSource>,
+        window: String,
+        /// Not playbook fields, set from [`ModuleOpts`]
+        #[serde(skip)]
+        event_time: Option<EventTimeField>,
+        #[serde(skip)]
+        allowed_lateness: Option<String>,
+        #[serde(skip, default = "late_stream")]
+        late_stream: String,
         name: String,
         input: Option<String>,
     },
@@ -165,12 +401,45 @@ pub enum Module {
         input: Option<String>,
     },
     Lookup {
+        table: String,
+        join: Vec<Join>,
+        lookup: Vec<String>,
+        #[serde(rename = "expectUniqueMatch", default)]
+        expect_unique_match: bool,
+        #[serde(rename = "eventTime", default, skip_serializing_if = "Option::is_none")]
+        event_time: Option<EventTimeField>,
+        /// Not a playbook field, the file of `table` in the `lookup_tables` of the config
+        #[serde(skip)]
+        table_file: Option<PathBuf>,
         name: String,
         input: Option<String>,
     },
 }
 
 impl Module {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Module::MessageIngestion { .. } => "MessageIngestion",
+            Module::FileIngestion { .. } => "FileIngestion",
+            Module::Splitting { .. } => "Splitting",
+            Module::Reporting { .. } => "Reporting",
+            Module::Logic { .. } => "Logic",
+            Module::Aggregation { .. } => "Aggregation",
+            Module::Deduplication { .. } => "Deduplication",
+            Module::Lookup { .. } => "Lookup",
+        }
+    }
+    pub fn streams(&self) -> impl Iterator<Item = &str> {
+        let routes = match self {
+            Module::Logic { routes, .. } => &routes[..],
+            Module::Aggregation { late_stream, .. } => std::slice::from_ref(late_stream),
+            _ => &[],
+        };
+        ["output", "error"]
+            .into_iter()
+            .chain(routes.iter().map(String::as_str))
+            .unique()
+    }
     pub fn name(&self) -> &str {
         match self {
             Module::MessageIngestion { name, .. } => name,
@@ -183,6 +452,38 @@ impl Module {
             Module::Lookup { name, .. } => name,
         }
     }
+    pub fn set_opts(&mut self, opts: &ModuleOpts) -> Result<(), String> {
+        let aggregation = opts.event_time.is_some()
+            || opts.allowed_lateness.is_some()
+            || opts.late_stream.is_some();
+        match self {
+            Module::Splitting { record_split, .. } if !aggregation => {
+                *record_split = opts.record_split
+            }
+            Module::Aggregation {
+                event_time,
+                allowed_lateness,
+                late_stream,
+                ..
+            } if !opts.record_split => {
+                event_time.clone_from(&opts.event_time);
+                allowed_lateness.clone_from(&opts.allowed_lateness);
+                if let Some(stream) = &opts.late_stream {
+                    late_stream.clone_from(stream);
+                }
+            }
+            _ if opts.record_split || aggregation => {
+                return Err(format!(
+                    "record_split is for Splitting modules, event_time, allowed_lateness and \
+                     late_stream for Aggregation modules, {} is {}",
+                    self.name(),
+                    self.kind()
+                ))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
     pub fn input(&self) -> Option<&str> {
         match self {
             Module::MessageIngestion { .. } => None,
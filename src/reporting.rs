@@ -0,0 +1,354 @@
+//! Reporting modules, and the simulated clock they run on.
+//!
+//! BMP gathers the messages of a Reporting module in cycles of its `scheduling.frequency`, and
+//! writes the report of a cycle `delayMinutes` after the cycle ends. The emulator has no real
+//! clock, time is the process date of the messages: a message goes in the cycle the clock is in
+//! when it arrives and moves the clock forward, so a message that arrives after its cycle closed
+//! is reported in the current one, like in production. Cycles are written as the clock passes
+//! their closing time, and at the end of the run every cycle that ends within the `period` of
+//! the config is, even the empty ones.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use jiff::{civil::Date, ToSpan};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    datapath::DataPath,
+    datetime::{Civil, DateTime, TimeZone},
+    opts::Message,
+    playbook::{ReportingFrequency, Scheduling},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    pub zone: TimeZone,
+    pub period: Option<(DateTime, DateTime)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cycle {
+    start: DateTime,
+    end: DateTime,
+}
+
+impl ReportingFrequency {
+    /// The cycle `time` is in. Days, weeks (from Monday) and months start at midnight in `zone`.
+    fn cycle(&self, time: &DateTime, zone: &TimeZone) -> Cycle {
+        let day = time.in_zone(zone).local().date();
+        let (start, end) = match self {
+            ReportingFrequency::Daily => (day, day.saturating_add(1.day())),
+            ReportingFrequency::Weekly => {
+                let days = day.weekday().to_monday_zero_offset();
+                let monday = day.saturating_sub(days.days());
+                (monday, monday.saturating_add(1.week()))
+            }
+            ReportingFrequency::Monthly => {
+                let first = day.first_of_month();
+                (first, first.saturating_add(1.month()))
+            }
+        };
+        // Every civil time has an instant, when clocks go forward at midnight it's the end of
+        // the gap
+        let midnight = |x: Date| DateTime::from_local(x.into(), zone).unwrap();
+        Cycle {
+            start: midnight(start),
+            end: midnight(end),
+        }
+    }
+
+    fn label(&self, start: &Civil) -> String {
+        match self {
+            ReportingFrequency::Daily => start.date().to_string(),
+            ReportingFrequency::Weekly => {
+                let week = start.date().iso_week_date();
+                format!("{:04}-W{:02}", week.year(), week.week())
+            }
+            ReportingFrequency::Monthly => format!("{:04}-{:02}", start.year(), start.month()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub label: String,
+    pub start: String,
+    pub end: String,
+    pub closed_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscriber: Option<String>,
+    pub rows: usize,
+    pub file: PathBuf,
+}
+
+type Rows = BTreeMap<String, Vec<Vec<String>>>;
+
+pub struct Reporter {
+    name: String,
+    frequency: ReportingFrequency,
+    delay: i64,
+    columns: Vec<(String, DataPath)>,
+    subscriber: Option<DataPath>,
+    clock: Clock,
+    dir: PathBuf,
+    now: Option<DateTime>,
+    next: Option<DateTime>,
+    rows: BTreeMap<DateTime, Rows>,
+    reports: Vec<Report>,
+}
+
+impl Reporter {
+    pub fn new(
+        name: &str,
+        scheduling: &Scheduling,
+        fields: &[BTreeMap<String, String>],
+        subscriber: Option<&str>,
+        clock: Clock,
+        dir: &Path,
+    ) -> Result<Self, String> {
+        let columns = fields
+            .iter()
+            .flatten()
+            .map(|(column, path)| match path.parse() {
+                Ok(path) => Ok((column.clone(), path)),
+                Err(e) => Err(format!("invalid path {path} for column {column}: {e}")),
+            })
+            .collect::<Result<_, _>>()?;
+        let subscriber = subscriber
+            .map(|x| {
+                x.parse()
+                    .map_err(|e| format!("invalid subscriber {x}: {e}"))
+            })
+            .transpose()?;
+        let frequency = scheduling.frequency;
+        let next = clock
+            .period
+            .map(|(start, _)| frequency.cycle(&start, &clock.zone).start);
+        Ok(Self {
+            name: name.to_owned(),
+            frequency,
+            delay: scheduling.delay_minutes as i64 * 60,
+            columns,
+            subscriber,
+            clock,
+            dir: dir.to_owned(),
+            now: None,
+            next,
+            rows: BTreeMap::new(),
+            reports: vec![],
+        })
+    }
+
+    pub fn push(&mut self, msg: &Message) -> Result<(), String> {
+        // Messages without a process date happen now
+        let time = match (msg.process_date(), self.now, self.clock.period) {
+            (Some(time), ..) => time,
+            (None, Some(now), _) => now,
+            (None, None, Some((start, _))) => start,
+            (None, None, None) => return Err("the message has no process date".to_owned()),
+        };
+        if let Some((start, end)) = self.clock.period {
+            if time < start || time >= end {
+                return Err(format!(
+                    "process date {time} is outside the period of the run"
+                ));
+            }
+        }
+        let subscriber = match &self.subscriber {
+            None => String::new(),
+            Some(path) => match path.get(msg) {
+                Ok(Value::String(x)) => x,
+                Ok(Value::Null) => return Err(format!("subscriber {path} is null")),
+                Ok(x) => x.to_string(),
+                Err(e) => return Err(format!("no subscriber: {e}")),
+            },
+        };
+        let row = self
+            .columns
+            .iter()
+            .map(|(_, path)| match path.get(msg) {
+                Ok(Value::String(x)) => x,
+                Ok(Value::Null) | Err(_) => String::new(),
+                Ok(x) => x.to_string(),
+            })
+            .collect();
+
+        let now = self.now.map_or(time, |now| now.max(time));
+        self.now = Some(now);
+        let cycle = self.frequency.cycle(&now, &self.clock.zone);
+        self.next.get_or_insert(cycle.start);
+        let rows = self.rows.entry(cycle.start).or_default();
+        rows.entry(subscriber).or_default().push(row);
+        let delay = self.delay;
+        self.close(|cycle| cycle.end.add_seconds(delay) <= now);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<usize> {
+        match self.clock.period {
+            // The run is over, there's nothing left to wait for
+            Some((_, end)) => self.close(|cycle| cycle.end <= end),
+            None => {
+                if let Some(&last) = self.rows.keys().last() {
+                    self.close(|cycle| cycle.start <= last);
+                }
+            }
+        }
+        for start in self.rows.keys() {
+            let label = self.frequency.label(&start.local());
+            warn!(
+                module = self.name,
+                "report {label} is still open at the end of the run, it's not written"
+            );
+        }
+
+        std::fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_string_pretty(&self.reports).expect("reports are valid json");
+        std::fs::write(self.dir.join(format!("{}.reports.json", self.name)), json)?;
+        Ok(self.reports.len())
+    }
+
+    fn close(&mut self, done: impl Fn(&Cycle) -> bool) {
+        while let Some(start) = self.next {
+            let cycle = self.frequency.cycle(&start, &self.clock.zone);
+            if !done(&cycle) {
+                break;
+            }
+            let rows = self.rows.remove(&cycle.start).unwrap_or_default();
+            self.write(&cycle, rows);
+            self.next = Some(cycle.end);
+        }
+    }
+
+    fn write(&mut self, cycle: &Cycle, mut rows: Rows) {
+        let label = self.frequency.label(&cycle.start.local());
+        let closed_at = cycle.end.add_seconds(self.delay);
+        // An empty cycle still has its report, but there's no telling who it's for
+        if rows.is_empty() && self.subscriber.is_none() {
+            rows.insert(String::new(), vec![]);
+        }
+        for (subscriber, rows) in rows {
+            let file = match self.subscriber {
+                None => format!("{}.{label}.csv", self.name),
+                Some(_) => format!("{}.{label}.{}.csv", self.name, file_name(&subscriber)),
+            };
+            let path = self.dir.join(&file);
+            if let Err(e) = write_csv(&path, &self.columns, &rows) {
+                error!("couldn't write the report {path:?}: {e}");
+                continue;
+            }
+            debug!(module = self.name, "report {file} closed at {closed_at}");
+            info!(
+                module = self.name,
+                "wrote report {label}, {} rows",
+                rows.len()
+            );
+            self.reports.push(Report {
+                label: label.clone(),
+                start: cycle.start.to_string(),
+                end: cycle.end.to_string(),
+                closed_at: closed_at.to_string(),
+                subscriber: self.subscriber.as_ref().map(|_| subscriber),
+                rows: rows.len(),
+                file: file.into(),
+            });
+        }
+    }
+}
+
+fn file_name(x: &str) -> String {
+    x.chars()
+        .map(|x| match x.is_alphanumeric() || "-_.".contains(x) {
+            true => x,
+            false => '_',
+        })
+        .collect()
+}
+
+fn write_csv(path: &Path, columns: &[(String, DataPath)], rows: &[Vec<String>]) -> io::Result<()> {
+    let escape = |x: &str| match x.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", x.replace('"', "\"\"")),
+        false => x.to_owned(),
+    };
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let mut w = BufWriter::new(std::fs::File::create(path)?);
+    let header = columns.iter().map(|x| escape(&x.0));
+    writeln!(w, "{}", header.collect::<Vec<_>>().join(","))?;
+    for row in rows {
+        let row = row.iter().map(|x| escape(x));
+        writeln!(w, "{}", row.collect::<Vec<_>>().join(","))?;
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opts::MessageInner;
+
+    fn msg(date: &str, payload: &str) -> Message {
+        Message {
+            inner: MessageInner::from(payload.to_owned()),
+            date: date.into(),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn monthly_cycles() {
+        let zone = "Europe/Paris".parse().unwrap();
+        let date = |x| DateTime::parse_iso(x, &zone).unwrap();
+        let clock = Clock {
+            zone: zone.clone(),
+            period: Some((date("2024-02-01"), date("2024-05-01"))),
+        };
+        let scheduling = Scheduling {
+            frequency: ReportingFrequency::Monthly,
+            delay_minutes: 60,
+        };
+        let fields = [BTreeMap::from([("id".to_owned(), "id".to_owned())])];
+        let dir = std::env::temp_dir().join(format!("reporting-{}", std::process::id()));
+        let mut reporter =
+            Reporter::new("report", &scheduling, &fields, None, clock, &dir).unwrap();
+
+        let messages = [
+            ("2024-03-05T10:00:00+01:00", "a"),
+            ("2024-03-31T23:30:00+02:00", "b,c"),
+            // Still within the delay of March, but a new cycle
+            ("2024-04-01T00:30:00+02:00", "d"),
+            // Late, March is over for the clock
+            ("2024-03-15T00:00:00+01:00", "e"),
+        ];
+        for (date, id) in messages {
+            let payload = serde_json::json!({ "id": id }).to_string();
+            reporter.push(&msg(date, &payload)).unwrap();
+        }
+        assert!(reporter
+            .push(&msg("2024-05-01T00:00:00+02:00", "{}"))
+            .is_err());
+        assert_eq!(reporter.finish().unwrap(), 3);
+
+        let read = |x| std::fs::read_to_string(dir.join(x)).unwrap();
+        assert_eq!(read("report.2024-02.csv"), "id\n");
+        assert_eq!(read("report.2024-03.csv"), "id\na\n\"b,c\"\n");
+        assert_eq!(read("report.2024-04.csv"), "id\nd\ne\n");
+        let index: Value = serde_json::from_str(&read("report.reports.json")).unwrap();
+        assert_eq!(index[1]["closed_at"], "2024-04-01T01:00:00+02:00");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let label = |x| {
+            let day = DateTime::from_local(jiff::civil::date(x, 12, 28).into(), &zone).unwrap();
+            let cycle = ReportingFrequency::Weekly.cycle(&day, &zone);
+            ReportingFrequency::Weekly.label(&cycle.start.local())
+        };
+        assert_eq!(label(2020), "2020-W53");
+        assert_eq!(label(2024), "2024-W52");
+        assert_eq!(label(2025), "2025-W52");
+    }
+}
@@ -0,0 +1,134 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path, time::Duration};
+
+use serde::{Serialize, Serializer};
+
+fn secs<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModuleMetrics {
+    pub name: String,
+    pub messages_in: u64,
+    pub streams: BTreeMap<String, u64>,
+    pub errors: u64,
+    #[serde(serialize_with = "secs")]
+    pub wall_time: Duration,
+    #[serde(serialize_with = "secs")]
+    pub js_time: Duration,
+}
+
+impl ModuleMetrics {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn messages_out(&self) -> u64 {
+        self.streams.values().sum()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunSummary {
+    pub messages_in: u64,
+    pub rejects: u64,
+    pub filtered: u64,
+    #[serde(serialize_with = "secs")]
+    pub wall_time: Duration,
+    #[serde(serialize_with = "secs")]
+    pub ingestion_time: Duration,
+    #[serde(serialize_with = "secs")]
+    pub anonymizer_time: Duration,
+    #[serde(serialize_with = "secs")]
+    pub js_time: Duration,
+    pub modules: Vec<ModuleMetrics>,
+}
+
+impl RunSummary {
+    pub fn write(&self, dir: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("summary is always valid json");
+        std::fs::write(dir.join("_summary.json"), json)
+    }
+}
+
+/// The counts of every module, `{:#}` adds the timings.
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timings = f.alternate();
+        let width = self
+            .modules
+            .iter()
+            .map(|m| m.name.len())
+            .chain([6])
+            .max()
+            .unwrap();
+        write!(
+            f,
+            "{:<width$} {:>10} {:>10} {:>8}",
+            "module", "in", "out", "errors"
+        )?;
+        if timings {
+            write!(f, " {:>10} {:>10}", "wall (s)", "js (s)")?;
+        }
+        writeln!(f)?;
+        for m in &self.modules {
+            write!(
+                f,
+                "{:<width$} {:>10} {:>10} {:>8}",
+                m.name,
+                m.messages_in,
+                m.messages_out(),
+                m.errors,
+            )?;
+            if timings {
+                write!(
+                    f,
+                    " {:>10.3} {:>10.3}",
+                    m.wall_time.as_secs_f64(),
+                    m.js_time.as_secs_f64()
+                )?;
+            }
+            writeln!(f)?;
+            for (stream, count) in &m.streams {
+                writeln!(f, "{:<width$} {:>10} {count:>10}", "", format!(".{stream}"))?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "messages in: {}", self.messages_in)?;
+        writeln!(f, "rejects:     {}", self.rejects)?;
+        write!(f, "filtered:    {}", self.filtered)?;
+        if timings {
+            writeln!(f)?;
+            writeln!(f, "ingestion:   {:.3}s", self.ingestion_time.as_secs_f64())?;
+            writeln!(f, "anonymizer:  {:.3}s", self.anonymizer_time.as_secs_f64())?;
+            writeln!(f, "js:          {:.3}s", self.js_time.as_secs_f64())?;
+            write!(f, "wall time:   {:.3}s", self.wall_time.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timings_only_when_asked() {
+        let summary = RunSummary {
+            messages_in: 3,
+            modules: vec![ModuleMetrics {
+                streams: BTreeMap::from([("output".to_owned(), 3)]),
+                messages_in: 3,
+                ..ModuleMetrics::new("split")
+            }],
+            ..Default::default()
+        };
+        let short = summary.to_string();
+        assert!(short.contains("split") && short.contains(".output"));
+        assert!(!short.contains("wall"));
+        assert!(format!("{summary:#}").contains("wall time:"));
+    }
+}
@@ -1,5 +1,7 @@
+use std::fmt::Display;
+
 use itertools::Itertools;
-use tracing::instrument;
+use tracing::{instrument, warn};
 
 use crate::playbook::Module;
 
@@ -75,6 +77,83 @@ impl PbTree {
         }
         ret
     }
+
+    pub fn detach(self, name: &str) -> Option<(PbTree, Option<(String, String)>)> {
+        if self.module.name() == name {
+            return Some((self, None));
+        }
+        let parent = self.module.name().to_owned();
+        for (child, route) in self.children {
+            if child.module.name() == name {
+                return Some((child, Some((parent, route))));
+            }
+            if let Some(found) = child.detach(name) {
+                return Some(found);
+            }
+        }
+        None
+    }
+
+    pub fn stop_after(&mut self, name: &str) {
+        if self.module.name() == name {
+            self.children.clear();
+        }
+        for (child, _) in &mut self.children {
+            child.stop_after(name);
+        }
+    }
+
+    /// Removes the modules in `names` from the tree. The children that read their `output` are
+    /// attached to their parent on the same route so their inputs pass through unchanged, unless
+    /// `stop` is set, in which case the whole branch is cut there. Children on other routes
+    /// never get anything then, they're cut too.
+    pub fn exclude(&mut self, names: &[String], stop: bool) {
+        let children = std::mem::take(&mut self.children);
+        for (mut child, route) in children {
+            child.exclude(names, stop);
+            if !names.iter().any(|x| x == child.module.name()) {
+                self.children.push((child, route));
+            } else if !stop {
+                for (node, from) in child.children {
+                    if from == "output" {
+                        self.children.push((node, route.clone()));
+                    } else {
+                        warn!(
+                            "{} reads {}.{from}, which is excluded, it won't get anything",
+                            node.module.name(),
+                            child.module.name()
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Display for PbTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn node(f: &mut std::fmt::Formatter<'_>, tree: &PbTree, prefix: &str) -> std::fmt::Result {
+            for (n, (child, route)) in tree.children.iter().enumerate() {
+                let last = n == tree.children.len() - 1;
+                let (branch, indent) = if last {
+                    ("└─", "   ")
+                } else {
+                    ("├─", "│  ")
+                };
+                let md = &child.module;
+                writeln!(
+                    f,
+                    "{prefix}{branch} {route} → {} ({})",
+                    md.name(),
+                    md.kind()
+                )?;
+                node(f, child, &format!("{prefix}{indent}"))?;
+            }
+            Ok(())
+        }
+        writeln!(f, "{} ({})", self.module.name(), self.module.kind())?;
+        node(f, self, "")
+    }
 }
 
 fn new(mods: &[Box<Module>]) -> () {
@@ -110,3 +189,55 @@ fn new(mods: &[Box<Module>]) -> () {
         cur
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(name: &str, children: Vec<(PbTree, &str)>) -> PbTree {
+        let module = serde_json::from_value(serde_json::json!({
+            "type": "Logic",
+            "name": name,
+            "routes": ["flow"],
+        }))
+        .unwrap();
+        PbTree {
+            module,
+            children: children
+                .into_iter()
+                .map(|(x, route)| (x, route.to_owned()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn excluded_modules_pass_their_output_through() {
+        let tree = node(
+            "root",
+            vec![(
+                node(
+                    "excluded",
+                    vec![
+                        (node("on_output", vec![]), "output"),
+                        (node("on_flow", vec![]), "flow"),
+                        (node("on_error", vec![]), "error"),
+                    ],
+                ),
+                "flow",
+            )],
+        );
+        let children = |tree: &PbTree| {
+            tree.children
+                .iter()
+                .map(|(x, route)| format!("{route} → {}", x.module.name()))
+                .collect_vec()
+        };
+
+        let mut passed = tree.clone();
+        passed.exclude(&["excluded".to_owned()], false);
+        assert_eq!(children(&passed), ["flow → on_output"]);
+        let mut stopped = tree;
+        stopped.exclude(&["excluded".to_owned()], true);
+        assert!(stopped.children.is_empty());
+    }
+}